@@ -1,34 +1,61 @@
 use core::fmt;
 use std::cmp::Reverse;
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
 use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{fs::OpenOptions, path::Path};
 
-use anyhow::{anyhow, ensure, Context, Result};
-use chrono::Local;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{BufMut, BytesMut};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime};
+use dialoguer::Password;
 use fs2::FileExt;
+use getopts::{Matches, Options};
 use log::warn;
 use regex::{Match, Regex};
 use serde::{Deserialize, Serialize};
 use strum::{EnumIter, EnumMessage, EnumString, IntoEnumIterator};
 
-use crate::cryptutil;
+use crate::{cryptutil, util};
 
+mod chunk;
+pub mod config;
 pub mod crypt;
 pub mod inbox;
 pub mod init;
+pub mod inspect;
 pub mod key;
+pub mod list;
+pub mod prune;
+pub mod recover;
+pub mod reindex;
+pub mod repair;
+pub mod restore;
 pub mod test_file;
+pub mod verify;
 
 const CONFIG_FILE_NAME: &str = "config.toml";
+const CONFIG_LOCK_NAME: &str = "config.toml.lock";
+/// config.toml.bak.<timestamp>
+const CONFIG_BACKUP_PREFIX: &str = "config.toml.bak.";
+/// The number of backups to be kept
+const CONFIG_BACKUP_COUNT: usize = 5;
+/// Derived key (permission 0600)
+const SECRETS_FILE_NAME: &str = "secrets.toml";
 const CRYPT_INFO_NAME: &str = "metadata.toml";
 const DIRNAME_INBOX: &str = "inbox";
 const DIRNAME_REPO: &str = "repo";
 const DIRNAME_CRYPT: &str = "crypt";
+/// crypt/.staging
+const DIRNAME_STAGING: &str = ".staging";
+/// crypt/chunks (shared by all tags)
+const DIRNAME_CHUNKS: &str = "chunks";
 
 const MD5EXT: &str = "md5sum";
+const PARITYEXT: &str = "parity";
+const MANIFESTEXT: &str = "manifest";
 
 #[derive(EnumString, EnumMessage, EnumIter)]
 enum CommandType {
@@ -40,6 +67,39 @@ enum CommandType {
     Inbox,
     #[strum(serialize = "crypt", message = "Split and encrypt files in repo/")]
     Crypt,
+    #[strum(serialize = "restore", message = "Restore files from crypt/")]
+    Restore,
+    #[strum(
+        serialize = "recover",
+        message = "Restore a file from a crypt/ directory without config"
+    )]
+    Recover,
+    #[strum(serialize = "verify", message = "Verify files in repo/ with MD5")]
+    Verify,
+    #[strum(serialize = "prune", message = "Remove old files in repo/")]
+    Prune,
+    #[strum(serialize = "list", message = "List files in the repository")]
+    List,
+    #[strum(
+        serialize = "config",
+        message = "Migrate config file or restore a backup"
+    )]
+    Config,
+    #[strum(
+        serialize = "reindex",
+        message = "Rebuild the index in config from repo/ and crypt/"
+    )]
+    Reindex,
+    #[strum(
+        serialize = "inspect",
+        message = "Print the header of an encrypted fragment"
+    )]
+    Inspect,
+    #[strum(
+        serialize = "repair",
+        message = "Regenerate lost fragments in crypt/ from parity"
+    )]
+    Repair,
 
     #[strum(serialize = "test-file", message = "Create test file(s) into inbox/")]
     TestFile,
@@ -59,6 +119,15 @@ pub fn dispatch_subcommand(basedir: impl AsRef<Path>, argv: &[String]) -> Result
         CommandType::Key => key::entry(basedir, cmd, args),
         CommandType::Inbox => inbox::entry(basedir, cmd, args),
         CommandType::Crypt => crypt::entry(basedir, cmd, args),
+        CommandType::Restore => restore::entry(basedir, cmd, args),
+        CommandType::Recover => recover::entry(basedir, cmd, args),
+        CommandType::Verify => verify::entry(basedir, cmd, args),
+        CommandType::Prune => prune::entry(basedir, cmd, args),
+        CommandType::List => list::entry(basedir, cmd, args),
+        CommandType::Config => config::entry(basedir, cmd, args),
+        CommandType::Reindex => reindex::entry(basedir, cmd, args),
+        CommandType::Inspect => inspect::entry(basedir, cmd, args),
+        CommandType::Repair => repair::entry(basedir, cmd, args),
         CommandType::TestFile => test_file::entry(basedir, cmd, args),
     }
 }
@@ -76,7 +145,7 @@ pub fn subcommands_help() -> String {
     help
 }
 
-const CONFIG_VERSION: u32 = 1;
+const CONFIG_VERSION: u32 = 2;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 struct Config {
@@ -86,6 +155,11 @@ struct Config {
     crypt: CryptType,
     #[serde(default)]
     repository: Repository,
+    #[serde(default)]
+    retention: RetentionPolicy,
+    /// key = tag name
+    #[serde(default)]
+    tags: BTreeMap<String, TagConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -121,10 +195,141 @@ enum CryptType {
         message = "AES128GCM-Argon2",
         detailed_message = "AES (symmetric-key block cipher) 128 bit (key-length) Galois/Counter Mode (+tampering detection) encryption."
     )]
-    Aes128GcmArgon2 {
-        key: Option<cryptutil::AesKey>,
-        argon2: Aes128GcmArgon2Param,
-    },
+    Aes128GcmArgon2(PassphraseKey),
+    #[strum(
+        serialize = "xchacha",
+        message = "XChaCha20Poly1305-Argon2",
+        detailed_message = "XChaCha20 (stream cipher, 192 bit nonce) Poly1305 (+tampering detection) encryption. Fast without AES hardware support."
+    )]
+    XChaCha20Poly1305Argon2(PassphraseKey),
+    #[strum(
+        serialize = "x25519",
+        message = "X25519-XChaCha20Poly1305",
+        detailed_message = "Public-key encryption to one or more recipients (--recipient). No secret is stored; the private key (key gen-keypair) is needed to decrypt."
+    )]
+    X25519(RecipientKeys),
+}
+
+impl CryptType {
+    /// None if PlainText
+    fn passphrase_key(&self) -> Option<&PassphraseKey> {
+        match self {
+            Self::PlainText | Self::X25519(_) => None,
+            Self::Aes128GcmArgon2(pk) | Self::XChaCha20Poly1305Argon2(pk) => Some(pk),
+        }
+    }
+
+    fn passphrase_key_mut(&mut self) -> Option<&mut PassphraseKey> {
+        match self {
+            Self::PlainText | Self::X25519(_) => None,
+            Self::Aes128GcmArgon2(pk) | Self::XChaCha20Poly1305Argon2(pk) => Some(pk),
+        }
+    }
+
+    /// Cipher for data (None if PlainText)
+    fn cipher(&self) -> Option<cryptutil::CipherId> {
+        match self {
+            Self::PlainText => None,
+            Self::Aes128GcmArgon2(_) => Some(cryptutil::CipherId::Aes256Gcm),
+            Self::XChaCha20Poly1305Argon2(_) | Self::X25519(_) => {
+                Some(cryptutil::CipherId::XChaCha20Poly1305)
+            }
+        }
+    }
+
+    /// The same cipher with another key.
+    ///
+    /// Unchanged if not passphrase-based.
+    fn with_key(&self, pk: PassphraseKey) -> Self {
+        match self {
+            Self::PlainText | Self::X25519(_) => self.clone(),
+            Self::Aes128GcmArgon2(_) => Self::Aes128GcmArgon2(pk),
+            Self::XChaCha20Poly1305Argon2(_) => Self::XChaCha20Poly1305Argon2(pk),
+        }
+    }
+}
+
+/// Public keys to encrypt to.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct RecipientKeys {
+    recipients: Vec<cryptutil::X25519Key>,
+    /// Data key wrapped for each recipient (crypt/ metadata only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    stanzas: Vec<RecipientStanza>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct RecipientStanza {
+    recipient: cryptutil::X25519Key,
+    /// Ephemeral public key for X25519 key agreement
+    ephemeral: cryptutil::X25519Key,
+    wrapped_key: WrappedKey,
+}
+
+impl RecipientKeys {
+    /// Wrap the data key for each recipient.
+    fn seal(&self, data_key: &cryptutil::AesKey) -> Result<Self> {
+        ensure!(!self.recipients.is_empty(), "No recipient");
+        let stanzas = self
+            .recipients
+            .iter()
+            .map(|recipient| {
+                let (ephemeral, kek) = cryptutil::x25519_kek_seal(recipient)?;
+                Ok(RecipientStanza {
+                    recipient: *recipient,
+                    ephemeral,
+                    wrapped_key: WrappedKey::wrap(&kek, data_key)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            recipients: self.recipients.clone(),
+            stanzas,
+        })
+    }
+
+    /// Unwrap the data key with the private key.
+    fn open(&self, secret: &cryptutil::X25519Key) -> Result<cryptutil::AesKey> {
+        let public = cryptutil::x25519_public(secret);
+        let stanza = self
+            .stanzas
+            .iter()
+            .find(|stanza| stanza.recipient == public)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Not encrypted to the private key (public key {})",
+                    x25519_key_to_str(&public)
+                )
+            })?;
+        let kek = cryptutil::x25519_kek_open(secret, &stanza.ephemeral)?;
+
+        stanza.wrapped_key.unwrap(&kek)
+    }
+}
+
+fn x25519_key_to_str(key: &cryptutil::X25519Key) -> String {
+    BASE64_STANDARD.encode(key)
+}
+
+fn x25519_key_from_str(s: &str) -> Result<cryptutil::X25519Key> {
+    let key = BASE64_STANDARD
+        .decode(s.trim())
+        .with_context(|| format!("Invalid key: {s}"))?;
+
+    key.try_into()
+        .map_err(|_| anyhow!("Invalid key length: {s}"))
+}
+
+/// Key settings of passphrase-based crypt types.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct PassphraseKey {
+    /// To check passphrase (the key itself is in [SECRETS_FILE_NAME] if saved)
+    key_check: Option<cryptutil::AesKeyCheck>,
+    argon2: Aes128GcmArgon2Param,
+    /// Data key wrapped by the passphrase-derived key.
+    /// If None, data is encrypted by the passphrase-derived key directly (old format).
+    wrapped_key: Option<WrappedKey>,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -135,27 +340,71 @@ struct Aes128GcmArgon2Param {
     p_cost: u32,
 }
 
+/// Random data key encrypted by the passphrase-derived key.
+///
+/// Changing the passphrase only needs to re-wrap this.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct WrappedKey {
+    nonce: cryptutil::AesNonce,
+    key: cryptutil::AesKey,
+    tag: cryptutil::AesTag,
+}
+
+impl WrappedKey {
+    fn wrap(kek: &cryptutil::AesKey, key: &cryptutil::AesKey) -> Result<Self> {
+        let (nonce, key, tag) = cryptutil::wrap_aeskey(kek, key)?;
+
+        Ok(Self { nonce, key, tag })
+    }
+
+    fn unwrap(&self, kek: &cryptutil::AesKey) -> Result<cryptutil::AesKey> {
+        cryptutil::unwrap_aeskey(kek, self.nonce, &self.key, &self.tag)
+            .context("Cannot unwrap the data key")
+    }
+}
+
 impl fmt::Display for CryptType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::PlainText => {
                 write!(f, "PlainText (no encryption)")?;
             }
-            Self::Aes128GcmArgon2 { key, argon2: param } => {
+            Self::Aes128GcmArgon2(pk) | Self::XChaCha20Poly1305Argon2(pk) => {
+                let PassphraseKey {
+                    key_check,
+                    argon2: param,
+                    wrapped_key,
+                } = pk;
                 let salt_str = param
                     .salt
                     .iter()
                     .fold(String::new(), |cur, b| cur + &format!("{:02x}", b));
-                writeln!(f, "AES")?;
+                if let Self::Aes128GcmArgon2(_) = self {
+                    writeln!(f, "AES")?;
+                } else {
+                    writeln!(f, "XChaCha20-Poly1305")?;
+                }
                 writeln!(f, "Key derived from passphrase by Argon2")?;
                 writeln!(f, "salt  : {salt_str}")?;
                 writeln!(f, "m_cost: {}", param.m_cost)?;
                 writeln!(f, "t_cost: {}", param.t_cost)?;
                 writeln!(f, "p_cost: {}", param.p_cost)?;
-                if key.is_some() {
-                    write!(f, "key   : SAVED (able to check passphrase)")?;
+                if wrapped_key.is_some() {
+                    writeln!(f, "data  : WRAPPED (random data key)")?;
                 } else {
-                    write!(f, "key   : NODATA (passphrase needed)")?;
+                    writeln!(f, "data  : DIRECT (encrypted by the derived key)")?;
+                }
+                if key_check.is_some() {
+                    write!(f, "check : SAVED (able to check passphrase)")?;
+                } else {
+                    write!(f, "check : NODATA (unable to check passphrase)")?;
+                }
+            }
+            Self::X25519(rk) => {
+                writeln!(f, "X25519 (public-key) + XChaCha20-Poly1305")?;
+                write!(f, "Data key wrapped for each recipient")?;
+                for recipient in rk.recipients.iter() {
+                    write!(f, "\nrecipient: {}", x25519_key_to_str(recipient))?;
                 }
             }
         }
@@ -176,12 +425,481 @@ struct RepositoryFile {
     crypt: bool,
 }
 
+/// Per-tag settings in `[tags.<name>]`.
+///
+/// Each setting overrides the repository-wide default if present.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct TagConfig {
+    /// Split fragment size for crypt (e.g. "64m")
+    fragment_size: Option<String>,
+    /// Retention policy for prune
+    retention: Option<RetentionPolicy>,
+    /// If false, crypt skips this tag
+    crypt: Option<bool>,
+    /// Expected backup interval (e.g. "1d", "1w")
+    interval: Option<String>,
+    /// Zstd level for crypt (0: no compression)
+    zstd_level: Option<i32>,
+    /// Reed-Solomon parity for crypt (e.g. "10+2": 2 parity per 10 fragments)
+    parity: Option<String>,
+    /// Content-defined chunking for crypt (deduplicated in crypt/chunks/)
+    chunked: Option<bool>,
+    /// Encrypt the newest N versions only (all if not set)
+    crypt_last: Option<u32>,
+}
+
+impl Config {
+    fn tag_config(&self, tag: &str) -> Option<&TagConfig> {
+        self.tags.get(tag)
+    }
+
+    fn retention_for(&self, tag: &str) -> &RetentionPolicy {
+        self.tag_config(tag)
+            .and_then(|tc| tc.retention.as_ref())
+            .unwrap_or(&self.retention)
+    }
+
+    fn crypt_enabled_for(&self, tag: &str) -> bool {
+        self.tag_config(tag).and_then(|tc| tc.crypt).unwrap_or(true)
+    }
+}
+
+/// Grandfather-father-son retention policy used by prune.
+///
+/// Each number is the count of generations to keep (0 = no rule).
+/// The latest version is always kept.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RetentionPolicy {
+    #[serde(default)]
+    last: u32,
+    #[serde(default)]
+    daily: u32,
+    #[serde(default)]
+    weekly: u32,
+    #[serde(default)]
+    monthly: u32,
+    #[serde(default)]
+    yearly: u32,
+}
+
+impl RetentionPolicy {
+    fn is_empty(&self) -> bool {
+        *self == Default::default()
+    }
+}
+
+/// Contents of [SECRETS_FILE_NAME].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Secrets {
+    /// The key is derived with this param
+    argon2: Aes128GcmArgon2Param,
+    key: cryptutil::AesKey,
+}
+
+/// Return None if not exists.
+fn read_secrets(dirpath: &Path) -> Result<Option<Secrets>> {
+    let path = dirpath.join(SECRETS_FILE_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = path.metadata()?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "{} is accessible by other users (mode {:o}), should be 600",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+
+    let toml = std::fs::read_to_string(&path)
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    let secrets =
+        toml::from_str(&toml).with_context(|| format!("Cannot parse {}", path.display()))?;
+
+    Ok(Some(secrets))
+}
+
+/// Write (permission 0600) or delete if None.
+fn write_secrets(dirpath: &Path, secrets: Option<&Secrets>) -> Result<()> {
+    let path = dirpath.join(SECRETS_FILE_NAME);
+    match secrets {
+        Some(secrets) => {
+            let toml = toml::to_string(secrets).unwrap();
+            util::write_atomic_private(&path, toml)
+                .with_context(|| format!("Cannot write {}", path.display()))?;
+        }
+        None => {
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Delete failed: {}", path.display()))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Used if no passphrase option is specified.
+const PASSPHRASE_ENV: &str = "BKUPMAN_PASSPHRASE";
+/// The current passphrase on change (key change-passphrase)
+const OLD_PASSPHRASE_ENV: &str = "BKUPMAN_OLD_PASSPHRASE";
+/// Options for the current passphrase on change: --old-passphrase-*
+const OLD_PASSPHRASE_PREFIX: &str = "old-";
+
+/// Where to get the passphrase from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum PassphraseSource {
+    /// Ask on the terminal
+    #[default]
+    Interactive,
+    /// The first line from the file descriptor
+    Fd(i32),
+    /// The first line in the file (permission 0600 or 0400)
+    File(PathBuf),
+    /// The environment variable ([PASSPHRASE_ENV] or [OLD_PASSPHRASE_ENV])
+    Env(&'static str),
+}
+
+impl PassphraseSource {
+    fn add_options(opts: &mut Options) {
+        Self::add_prefixed_options(opts, "");
+    }
+
+    /// --<prefix>passphrase-fd and --<prefix>passphrase-file
+    fn add_prefixed_options(opts: &mut Options, prefix: &str) {
+        let what = format!("{}passphrase", prefix.replace('-', " "));
+        opts.optopt(
+            "",
+            &format!("{prefix}passphrase-fd"),
+            &format!("Read {what} from the file descriptor"),
+            "<FD>",
+        );
+        opts.optopt(
+            "",
+            &format!("{prefix}passphrase-file"),
+            &format!("Read {what} from the file (must not be accessible by others)"),
+            "<FILE>",
+        );
+    }
+
+    /// Options > environment variable > interactive
+    fn from_matches(matches: &Matches) -> Result<Self> {
+        Self::from_prefixed_matches(matches, "", PASSPHRASE_ENV)
+    }
+
+    /// [Self::from_matches] for [Self::add_prefixed_options]
+    fn from_prefixed_matches(matches: &Matches, prefix: &str, env: &'static str) -> Result<Self> {
+        let fd = matches.opt_str(&format!("{prefix}passphrase-fd"));
+        let file = matches.opt_str(&format!("{prefix}passphrase-file"));
+        ensure!(
+            fd.is_none() || file.is_none(),
+            "--{prefix}passphrase-fd and --{prefix}passphrase-file are exclusive"
+        );
+
+        if let Some(fd) = fd {
+            let fd: i32 = fd
+                .parse()
+                .with_context(|| format!("Invalid file descriptor: {fd}"))?;
+            ensure!(fd >= 0, "Invalid file descriptor: {fd}");
+            Ok(Self::Fd(fd))
+        } else if let Some(file) = file {
+            Ok(Self::File(PathBuf::from(file)))
+        } else if std::env::var_os(env).is_some() {
+            Ok(Self::Env(env))
+        } else {
+            Ok(Self::Interactive)
+        }
+    }
+
+    fn is_interactive(&self) -> bool {
+        *self == Self::Interactive
+    }
+
+    /// Input twice if `confirm` (interactive only).
+    ///
+    /// Fd can be read only once.
+    fn read(&self, prompt: &str, confirm: bool) -> Result<String> {
+        let passphrase = match self {
+            Self::Interactive => {
+                let mut input = Password::new()
+                    .with_prompt(prompt)
+                    .allow_empty_password(true);
+                if confirm {
+                    input = input.with_confirmation("Input again", "Passphrase mismatch");
+                }
+                return Ok(input.interact()?);
+            }
+            Self::Fd(fd) => read_fd_to_string(*fd)
+                .with_context(|| format!("Cannot read passphrase from fd {fd}"))?,
+            Self::File(path) => {
+                ensure_private_file(path)?;
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Cannot read {}", path.display()))?
+            }
+            Self::Env(env) => {
+                std::env::var(env).with_context(|| format!("Cannot read passphrase from ${env}"))?
+            }
+        };
+
+        // the first line without "\n" or "\r\n" (fd is read up to "\n")
+        let line = passphrase.split('\n').next().unwrap_or_default();
+        Ok(line.strip_suffix('\r').unwrap_or(line).to_string())
+    }
+}
+
+/// Error if group or others can access the file.
+fn ensure_private_file(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = path
+            .metadata()
+            .with_context(|| format!("Cannot read {}", path.display()))?
+            .permissions()
+            .mode();
+        ensure!(
+            mode & 0o077 == 0,
+            "{} is accessible by other users (mode {:o}), should be 600",
+            path.display(),
+            mode & 0o777
+        );
+    }
+
+    Ok(())
+}
+
+/// Private key file for [CryptType::X25519].
+fn add_identity_option(opts: &mut Options) {
+    opts.optopt(
+        "i",
+        "identity",
+        "Private key file to decrypt x25519 data (see key gen-keypair)",
+        "<FILE>",
+    );
+}
+
+/// Private key file format:
+///
+/// ```text
+/// # public key: <base64>
+/// <base64 secret key>
+/// ```
+fn write_identity(path: &Path, secret: &cryptutil::X25519Key) -> Result<()> {
+    ensure!(!path.exists(), "Already exists: {}", path.display());
+    let public = cryptutil::x25519_public(secret);
+    let text = format!(
+        "# public key: {}\n{}\n",
+        x25519_key_to_str(&public),
+        x25519_key_to_str(secret)
+    );
+
+    util::write_atomic_private(path, text)
+        .with_context(|| format!("Cannot write {}", path.display()))
+}
+
+fn read_identity(path: &Path) -> Result<cryptutil::X25519Key> {
+    ensure_private_file(path)?;
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let line = text
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| anyhow!("No key found: {}", path.display()))?;
+
+    x25519_key_from_str(line).with_context(|| format!("Invalid private key: {}", path.display()))
+}
+
+/// Read the first line (up to the newline, EOF is not needed).
+///
+/// The fd is not closed and nothing after the newline is consumed.
+#[cfg(unix)]
+fn read_fd_to_string(fd: i32) -> Result<String> {
+    use std::io::Read;
+    use std::os::fd::BorrowedFd;
+
+    const LINE_MAX: usize = 4096;
+
+    ensure!(fd >= 0, "Invalid file descriptor: {fd}");
+    // SAFETY: F_GETFD only queries the descriptor flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: fd is open (checked above) and only borrowed to be duplicated
+    let owned = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    // closes the duplicate only
+    let mut file = File::from(owned);
+
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        ensure!(buf.len() < LINE_MAX, "Too long line");
+        buf.push(byte[0]);
+    }
+
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(not(unix))]
+fn read_fd_to_string(_fd: i32) -> Result<String> {
+    anyhow::bail!("--passphrase-fd is not supported on this platform")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CryptInfo {
     crypt: CryptType,
-    /// fragment count = [Self::total_size] + [Self::fragment_size]
+    /// fragment count = [Self::total_size] / [Self::fragment_size] (round up)
     total_size: u64,
     fragment_size: NonZeroU64,
+    /// Fragment identity is authenticated as AES-GCM AAD (false: old format)
+    #[serde(default)]
+    aad: bool,
+    /// [cryptutil::FragmentHeader] version (0: old format without magic)
+    #[serde(default)]
+    header_version: u16,
+    /// Each fragment is compressed before encryption if Some
+    #[serde(default)]
+    compression: Option<Compression>,
+    /// Reed-Solomon parity fragments if Some
+    #[serde(default)]
+    parity: Option<ParityInfo>,
+    /// Chunked layout (manifest and shared chunk store) if Some
+    #[serde(default)]
+    chunks: Option<ChunkInfo>,
+}
+
+/// Content-defined chunks stored once in crypt/chunks/.
+///
+/// The encrypted manifest ("name.manifest") lists the chunks in order;
+/// only the IDs are recorded here to count references without keys.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChunkInfo {
+    /// Referenced chunk IDs (sorted, unique)
+    refs: Vec<String>,
+}
+
+/// Erasure coding over the stored fragment files.
+///
+/// Data fragments are grouped by [Self::data_shards] (the last group may be
+/// smaller) and each group has [Self::parity_shards] parity fragments,
+/// numbered through all groups.
+/// Shards are zero-padded to the largest fragment in the group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ParityInfo {
+    data_shards: usize,
+    parity_shards: usize,
+    /// Stored size of each data fragment
+    sizes: Vec<u64>,
+    /// MD5 of each data fragment (damage is found without keys)
+    data_md5: Vec<String>,
+    /// MD5 of each parity fragment
+    parity_md5: Vec<String>,
+}
+
+/// Compression stage of crypt.
+///
+/// Fragments are compressed independently,
+/// so the stored size is not predictable from [CryptInfo::total_size].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm")]
+enum Compression {
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd { level } => Ok(zstd::bulk::compress(data, *level)?),
+        }
+    }
+
+    /// Error if the result exceeds max_size (one fragment).
+    fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd { .. } => Ok(zstd::bulk::decompress(data, max_size)?),
+        }
+    }
+}
+
+impl CryptInfo {
+    /// 0 if chunked (no fragment file)
+    fn fragment_count(&self) -> u64 {
+        if self.chunks.is_some() {
+            return 0;
+        }
+        self.total_size.div_ceil(self.fragment_size.get())
+    }
+}
+
+impl Aes128GcmArgon2Param {
+    fn from_header(header: &cryptutil::FragmentHeader) -> Self {
+        Self {
+            salt: header.salt,
+            m_cost: header.m_cost,
+            t_cost: header.t_cost,
+            p_cost: header.p_cost,
+        }
+    }
+
+    fn to_header(
+        &self,
+        cipher: cryptutil::CipherId,
+        index: u64,
+        nonce: Vec<u8>,
+    ) -> cryptutil::FragmentHeader {
+        cryptutil::FragmentHeader::new(
+            cipher,
+            self.salt,
+            self.m_cost,
+            self.t_cost,
+            self.p_cost,
+            index,
+            nonce,
+        )
+    }
+}
+
+/// "name.000000", "name.000001", ...
+fn fragment_name(name: &str, idx: u64) -> String {
+    format!("{}.{:0>6}", name, idx)
+}
+
+/// "name.000000.md5sum", "name.000001.md5sum", ... (PlainText only)
+fn fragment_md5_name(name: &str, idx: u64) -> String {
+    format!("{}.{}", fragment_name(name, idx), MD5EXT)
+}
+
+/// "name.000000.parity", "name.000001.parity", ...
+fn fragment_parity_name(name: &str, pidx: u64) -> String {
+    format!("{}.{}", fragment_name(name, pidx), PARITYEXT)
+}
+
+/// "name.manifest" (chunked layout)
+fn manifest_name(name: &str) -> String {
+    format!("{}.{}", name, MANIFESTEXT)
+}
+
+/// AES-GCM associated data to bind a fragment to its position.
+///
+/// Swapped, reordered, duplicated or truncated fragments (even between tags)
+/// fail to decrypt.
+/// The last fragment is marked as in the STREAM construction.
+fn fragment_aad(tag: &str, name: &str, idx: u64, count: u64) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    buf.put_u32_le(tag.len() as u32);
+    buf.put(tag.as_bytes());
+    buf.put_u32_le(name.len() as u32);
+    buf.put(name.as_bytes());
+    buf.put_u64_le(idx);
+    buf.put_u64_le(count);
+    buf.put_u8((idx + 1 == count) as u8);
+
+    buf.to_vec()
 }
 
 impl Default for System {
@@ -199,36 +917,175 @@ impl System {
     }
 }
 
+/// Lock the repository (unlocked when the returned file is closed).
+///
+/// config.toml itself cannot be locked because it is replaced by rename.
+fn lock_config(dirpath: &Path) -> Result<File> {
+    let lockpath = dirpath.join(CONFIG_LOCK_NAME);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lockpath)
+        .with_context(|| format!("Cannot open {}", lockpath.display()))?;
+    file.try_lock_exclusive()
+        .with_context(|| format!("Cannot lock {}", lockpath.display()))?;
+
+    Ok(file)
+}
+
+/// Read system.version (1 if not found).
+fn config_version(table: &toml::Table) -> Result<u32> {
+    let Some(version) = table.get("system").and_then(|system| system.get("version")) else {
+        return Ok(1);
+    };
+    let version = version
+        .as_integer()
+        .and_then(|v| u32::try_from(v).ok())
+        .ok_or_else(|| anyhow!("Invalid config version: {version}"))?;
+
+    Ok(version)
+}
+
+/// Read and check version.
+fn read_config(tomlpath: &Path) -> Result<Config> {
+    let toml = std::fs::read_to_string(tomlpath)
+        .with_context(|| format!("Cannot read {}", tomlpath.display()))?;
+    let table: toml::Table =
+        toml::from_str(&toml).with_context(|| format!("Cannot parse {}", tomlpath.display()))?;
+
+    let version = config_version(&table)?;
+    ensure!(
+        version <= CONFIG_VERSION,
+        "Config version {version} is newer than supported ({CONFIG_VERSION}), update bkupman"
+    );
+    ensure!(
+        version == CONFIG_VERSION,
+        "Config version {version} is old (current {CONFIG_VERSION}), run `bkupman config migrate`"
+    );
+
+    let config =
+        toml::from_str(&toml).with_context(|| format!("Cannot parse {}", tomlpath.display()))?;
+
+    Ok(config)
+}
+
+/// Backup file names in the directory (oldest first).
+fn list_config_backups(dirpath: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in dirpath.read_dir()? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if name.starts_with(CONFIG_BACKUP_PREFIX) && !name.ends_with(".tmp") {
+                names.push(name.to_string());
+            }
+        }
+    }
+    // timestamp order
+    names.sort();
+
+    Ok(names)
+}
+
+/// Create a new empty config.toml.bak.<timestamp>[-NNN] and return the path.
+///
+/// The suffix is added if saved twice in the same millisecond.
+fn reserve_config_backup(dirpath: &Path) -> Result<PathBuf> {
+    const RETRY_MAX: u32 = 1000;
+
+    let timestamp = Local::now().format("%Y%m%d%H%M%S%3f");
+    for seq in 0..RETRY_MAX {
+        // sorted in timestamp order
+        let name = if seq == 0 {
+            format!("{CONFIG_BACKUP_PREFIX}{timestamp}")
+        } else {
+            format!("{CONFIG_BACKUP_PREFIX}{timestamp}-{seq:03}")
+        };
+        let path = dirpath.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("Cannot create {}", path.display()))
+            }
+        }
+    }
+    bail!("Too many backups at {timestamp}");
+}
+
+/// Copy config.toml to config.toml.bak.<timestamp>
+/// and remove old ones except the last [CONFIG_BACKUP_COUNT].
+fn backup_config(dirpath: &Path) -> Result<()> {
+    let tomlpath = dirpath.join(CONFIG_FILE_NAME);
+    let toml =
+        std::fs::read(&tomlpath).with_context(|| format!("Cannot read {}", tomlpath.display()))?;
+
+    let bakpath = reserve_config_backup(dirpath)?;
+    util::write_atomic(&bakpath, toml)
+        .with_context(|| format!("Cannot write {}", bakpath.display()))?;
+
+    let names = list_config_backups(dirpath)?;
+    let remove_count = names.len().saturating_sub(CONFIG_BACKUP_COUNT);
+    for name in &names[..remove_count] {
+        let path = dirpath.join(name);
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Delete failed: {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Backup and replace config.toml atomically (the lock must be held).
+fn save_config(dirpath: &Path, config: &Config) -> Result<()> {
+    let tomlpath = dirpath.join(CONFIG_FILE_NAME);
+    let toml = toml::to_string(config).unwrap();
+
+    backup_config(dirpath)?;
+    util::write_atomic(&tomlpath, toml)
+        .with_context(|| format!("Cannot write {}", tomlpath.display()))?;
+
+    Ok(())
+}
+
 /// Do process with locking config file.
 ///
-/// 1. Open and exclusive-lock dirpath/config.toml
+/// 1. Exclusive-lock dirpath/config.toml.lock
+/// 1. Read dirpath/config.toml
+/// 1. Commit or delete crypt/ metadata left by an interrupted change-passphrase
 /// 1. Call proc
-/// 1. If proc returns Some, overwrite to dirpath/config.toml
+/// 1. If proc returns Some, backup and replace dirpath/config.toml
 fn process_with_config_lock(
     dirpath: impl AsRef<Path>,
     proc: impl FnOnce(&Path, Config) -> Result<Option<Config>>,
 ) -> Result<()> {
-    let tomlpath = dirpath.as_ref().join(CONFIG_FILE_NAME);
+    let dirpath = dirpath.as_ref();
+    let tomlpath = dirpath.join(CONFIG_FILE_NAME);
     {
-        // open with R/W
-        let mut file = OpenOptions::new().read(true).write(true).open(tomlpath)?;
-        file.try_lock_exclusive()?;
-        let mut toml = String::new();
-        file.read_to_string(&mut toml)?;
-        let config: Config = toml::from_str(&toml)?;
+        let _lock = lock_config(dirpath)?;
+        let config = read_config(&tomlpath)?;
+        key::clean_rewrap(dirpath, &config)?;
 
         // if config is returned, overwrite (still locked)
-        if let Some(config) = proc(dirpath.as_ref(), config)? {
-            let toml = toml::to_string(&config).unwrap();
-            file.seek(std::io::SeekFrom::Start(0))?;
-            file.set_len(0)?;
-            file.write_all(toml.as_bytes())?;
+        if let Some(config) = proc(dirpath, config)? {
+            save_config(dirpath, &config)?;
         }
         // unlock and close
     }
     Ok(())
 }
 
+/// Read dirpath/config.toml with locking and unlock.
+///
+/// For read-only commands which take long (other commands are not blocked).
+fn read_config_with_lock(dirpath: impl AsRef<Path>) -> Result<Config> {
+    let dirpath = dirpath.as_ref();
+    let _lock = lock_config(dirpath)?;
+    let config = read_config(&dirpath.join(CONFIG_FILE_NAME))?;
+    key::clean_rewrap(dirpath, &config)?;
+
+    Ok(config)
+}
+
 fn process_with_config_lock_force_save(
     dirpath: impl AsRef<Path>,
     proc: impl FnOnce(&Path, Config) -> (Option<Config>, Result<()>),
@@ -281,6 +1138,26 @@ fn split_filename(name: &str) -> Result<(&str, &str, &str)> {
     Ok((s1, slice(name, caps.get(2)), slice(name, caps.get(3))))
 }
 
+/// Parse the date part of [split_filename].
+///
+/// YYYYMMDD[hhmmss]
+fn parse_date(date: &str) -> Result<NaiveDateTime> {
+    ensure!(
+        date.len() == 8 || date.len() == 14,
+        "Invalid date (YYYYMMDD[hhmmss]): {date}"
+    );
+    let ymd = NaiveDate::parse_from_str(&date[..8], "%Y%m%d")
+        .with_context(|| format!("Invalid date: {date}"))?;
+    let hms = if date.len() == 14 {
+        NaiveTime::parse_from_str(&date[8..], "%H%M%S")
+            .with_context(|| format!("Invalid time: {date}"))?
+    } else {
+        NaiveTime::MIN
+    };
+
+    Ok(NaiveDateTime::new(ymd, hms))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,4 +1182,139 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_tag_config() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [retention]
+            last = 3
+
+            [tags.a]
+            crypt = false
+            retention = { daily = 7 }
+
+            [tags.b]
+            fragment_size = "1g"
+            zstd_level = 19
+            "#,
+        )?;
+
+        assert!(!config.crypt_enabled_for("a"));
+        assert!(config.crypt_enabled_for("b"));
+        assert!(config.crypt_enabled_for("c"));
+
+        assert_eq!(config.retention_for("a").daily, 7);
+        assert_eq!(config.retention_for("a").last, 0);
+        assert_eq!(config.retention_for("b").last, 3);
+        assert_eq!(config.retention_for("c").last, 3);
+
+        assert_eq!(config.tag_config("a").unwrap().zstd_level, None);
+        assert_eq!(config.tag_config("b").unwrap().zstd_level, Some(19));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date() -> Result<()> {
+        let dt = parse_date("20240613")?;
+        assert_eq!(dt.to_string(), "2024-06-13 00:00:00");
+        let dt = parse_date("20240613165945")?;
+        assert_eq!(dt.to_string(), "2024-06-13 16:59:45");
+
+        assert!(parse_date("2024061").is_err());
+        assert!(parse_date("20241313").is_err());
+        assert!(parse_date("20240613256000").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_passphrase_source() -> Result<()> {
+        let tmpdir = tempdir::TempDir::new("bkupman-test")?;
+        let path = tmpdir.path().join("pass");
+        std::fs::write(&path, "secret\nignored\n")?;
+
+        let mut opts = Options::new();
+        PassphraseSource::add_options(&mut opts);
+        let pathstr = path.to_str().unwrap();
+        let matches = opts.parse(["--passphrase-file", pathstr])?;
+        let source = PassphraseSource::from_matches(&matches)?;
+        assert_eq!(source, PassphraseSource::File(path.clone()));
+        let matches = opts.parse(["--passphrase-file", pathstr, "--passphrase-fd", "3"])?;
+        assert!(PassphraseSource::from_matches(&matches).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            use std::os::unix::fs::PermissionsExt;
+
+            // readable by others
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+            assert!(source.read("Passphrase", false).is_err());
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            assert_eq!(source.read("Passphrase", false)?, "secret");
+            std::fs::write(&path, "secret\r\nignored\r\n")?;
+            assert_eq!(source.read("Passphrase", false)?, "secret");
+
+            let file = File::open(&path)?;
+            let source = PassphraseSource::Fd(file.as_raw_fd());
+            assert_eq!(source.read("Passphrase", false)?, "secret");
+
+            // the writer is not closed: the first line only (no wait for EOF)
+            let (reader, mut writer) = std::io::pipe()?;
+            std::io::Write::write_all(&mut writer, b"piped\nnext\r\nlast\n")?;
+            let source = PassphraseSource::Fd(reader.as_raw_fd());
+            assert_eq!(source.read("Passphrase", false)?, "piped");
+            // CRLF as the file
+            assert_eq!(source.read("Passphrase", false)?, "next");
+            assert_eq!(source.read("Passphrase", false)?, "last");
+
+            // not open
+            assert!(PassphraseSource::Fd(1_000_000)
+                .read("Passphrase", false)
+                .is_err());
+        }
+        let matches = opts.parse(["--passphrase-fd", "-1"])?;
+        assert!(PassphraseSource::from_matches(&matches).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_keys() -> Result<()> {
+        let tmpdir = tempdir::TempDir::new("bkupman-test")?;
+        let path = tmpdir.path().join("identity");
+
+        let (secret1, public1) = cryptutil::x25519_keypair_new();
+        let (secret2, public2) = cryptutil::x25519_keypair_new();
+        let (secret3, _) = cryptutil::x25519_keypair_new();
+        write_identity(&path, &secret1)?;
+        assert!(write_identity(&path, &secret2).is_err());
+        assert_eq!(read_identity(&path)?, secret1);
+        assert_eq!(x25519_key_from_str(&x25519_key_to_str(&public1))?, public1);
+        assert!(x25519_key_from_str("AAAA").is_err());
+
+        let rk = RecipientKeys {
+            recipients: vec![public1, public2],
+            stanzas: Vec::new(),
+        };
+        let data_key: cryptutil::AesKey = cryptutil::generate_random();
+        let sealed = rk.seal(&data_key)?;
+        assert_eq!(sealed.stanzas.len(), 2);
+        assert_eq!(sealed.open(&secret1)?, data_key);
+        assert_eq!(sealed.open(&secret2)?, data_key);
+        assert!(sealed.open(&secret3).is_err());
+        assert!(rk.open(&secret1).is_err());
+        assert!(RecipientKeys::default().seal(&data_key).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+            assert!(read_identity(&path).is_err());
+        }
+
+        Ok(())
+    }
 }
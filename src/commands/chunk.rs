@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use super::restore::{self, KeyCache};
+use super::{Aes128GcmArgon2Param, Compression, CryptInfo};
+use crate::cryptutil::{self, AesKey, ChunkId, ChunkIdKey, CipherId, FragmentHeader, KdfId};
+use crate::util;
+
+/// FastCDC chunk sizes
+pub(super) const CHUNK_MIN: u32 = 256 * 1024;
+pub(super) const CHUNK_AVG: u32 = 1024 * 1024;
+pub(super) const CHUNK_MAX: u32 = 4 * 1024 * 1024;
+
+/// The first byte of chunk plain data (before encryption).
+///
+/// A chunk is shared by files with different compression settings,
+/// so each chunk records its own encoding.
+const CODEC_RAW: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+const TMPEXT: &str = "tmp";
+
+/// Chunks of a file in order (encrypted in "name.manifest").
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    chunks: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ManifestEntry {
+    id: String,
+    /// Plain size
+    size: u64,
+}
+
+impl Manifest {
+    pub(super) fn push(&mut self, id: String, size: u64) {
+        self.chunks.push(ManifestEntry { id, size });
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Referenced chunk IDs (sorted, unique)
+    pub(super) fn refs(&self) -> Vec<String> {
+        let mut refs: Vec<_> = self.chunks.iter().map(|ent| ent.id.clone()).collect();
+        refs.sort();
+        refs.dedup();
+
+        refs
+    }
+}
+
+pub(super) fn id_to_str(id: &ChunkId) -> String {
+    id.iter().map(|b| format!("{:0>2x}", b)).collect()
+}
+
+/// crypt/chunks/ab/abcdef...
+fn chunk_path(chunks_path: &Path, id: &str) -> PathBuf {
+    chunks_path.join(&id[..2]).join(id)
+}
+
+/// crypt/tag/name (or crypt/tag of the old layout) -> crypt/chunks
+fn chunks_path_of(src_dir_path: &Path) -> PathBuf {
+    let mut candidates = src_dir_path
+        .ancestors()
+        .skip(1)
+        .take(2)
+        .map(|path| path.join(super::DIRNAME_CHUNKS));
+    let parent = candidates.next().unwrap_or_default();
+    match candidates.next() {
+        Some(path) if !parent.is_dir() => path,
+        _ => parent,
+    }
+}
+
+/// A chunk is bound to its ID (not to tag, shared).
+fn chunk_aad(id: &str) -> Vec<u8> {
+    let mut aad = b"bkupman-chunk\0".to_vec();
+    aad.extend_from_slice(id.as_bytes());
+
+    aad
+}
+
+/// A manifest is bound to its tag and file name.
+fn manifest_aad(tag: &str, name: &str) -> Vec<u8> {
+    let mut aad = b"bkupman-manifest\0".to_vec();
+    aad.extend(super::fragment_aad(tag, name, 0, 1));
+
+    aad
+}
+
+/// header + ciphertext
+fn seal(
+    key: &AesKey,
+    cipher: CipherId,
+    argon2: &Aes128GcmArgon2Param,
+    plain: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let (nonce, encbuf) = cipher.encrypt(key, plain, aad)?;
+    let mut buf = argon2.to_header(cipher, 0, nonce).to_bytes();
+    buf.extend(encbuf);
+
+    Ok(buf)
+}
+
+fn open(key: &AesKey, cipher: CipherId, buf: &[u8], aad: &[u8], path: &Path) -> Result<Vec<u8>> {
+    let header = FragmentHeader::parse(buf)
+        .with_context(|| format!("Invalid header: {}", path.display()))?;
+    ensure!(
+        header.cipher == cipher && header.kdf == KdfId::Argon2id,
+        "Cipher unmatch (actual {:?}, {:?}): {}",
+        header.cipher,
+        header.kdf,
+        path.display()
+    );
+
+    header
+        .cipher
+        .decrypt(key, &header.nonce, &buf[header.header_len()..], aad)
+        .with_context(|| format!("Decryption failed: {}", path.display()))
+}
+
+/// Encrypt and store a chunk into chunks_path unless it already exists.
+///
+/// Return true if newly stored.
+pub(super) async fn write_chunk(
+    chunks_path: &Path,
+    id: &str,
+    data: Vec<u8>,
+    key: &AesKey,
+    cipher: CipherId,
+    argon2: &Aes128GcmArgon2Param,
+    compression: Option<Compression>,
+) -> Result<bool> {
+    let path = chunk_path(chunks_path, id);
+    if tokio::fs::try_exists(&path).await? {
+        debug!("Exists: {}", path.display());
+        return Ok(false);
+    }
+
+    let (key, argon2, aad) = (*key, argon2.clone(), chunk_aad(id));
+    let buf = util::blocking(move || {
+        let mut plain = Vec::with_capacity(data.len() + 1);
+        match compression {
+            Some(compression) => {
+                plain.push(CODEC_ZSTD);
+                plain.extend(compression.compress(&data)?);
+            }
+            None => {
+                plain.push(CODEC_RAW);
+                plain.extend_from_slice(&data);
+            }
+        }
+        drop(data);
+        seal(&key, cipher, &argon2, &plain, &aad)
+    })
+    .await?;
+
+    // the same chunk may be written by another task at the same time
+    let dir_path = path.parent().unwrap();
+    tokio::fs::create_dir_all(dir_path)
+        .await
+        .with_context(|| format!("Mkdir failed: {}", dir_path.display()))?;
+    let tmp_path = dir_path.join(format!("{id}.{:016x}.{TMPEXT}", util::seed64()));
+    let mut fout = tokio::fs::File::create_new(&tmp_path).await?;
+    fout.write_all(&buf).await?;
+    fout.sync_all().await?;
+    drop(fout);
+    tokio::fs::rename(&tmp_path, &path)
+        .await
+        .with_context(|| format!("Rename failed: {}", tmp_path.display()))?;
+    debug!("To: {}", path.display());
+
+    Ok(true)
+}
+
+/// Read, decrypt and verify a chunk.
+async fn read_chunk(
+    chunks_path: &Path,
+    id: &str,
+    key: &AesKey,
+    id_key: &ChunkIdKey,
+    cipher: CipherId,
+) -> Result<Vec<u8>> {
+    let path = chunk_path(chunks_path, id);
+    let buf = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    let plain = open(key, cipher, &buf, &chunk_aad(id), &path)?;
+
+    let data = match plain.split_first() {
+        Some((&CODEC_RAW, data)) => data.to_vec(),
+        Some((&CODEC_ZSTD, data)) => Compression::Zstd { level: 0 }
+            .decompress(data, CHUNK_MAX as usize)
+            .with_context(|| format!("Decompression failed: {}", path.display()))?,
+        _ => bail!("Unknown chunk encoding: {}", path.display()),
+    };
+    ensure!(
+        id_to_str(&cryptutil::chunk_id(id_key, &data)) == id,
+        "Chunk ID unmatch: {}",
+        path.display()
+    );
+
+    Ok(data)
+}
+
+pub(super) async fn write_manifest(
+    dst_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    manifest: &Manifest,
+    key: &AesKey,
+    cipher: CipherId,
+    argon2: &Aes128GcmArgon2Param,
+) -> Result<()> {
+    let plain = toml::to_string(manifest)?;
+    let buf = seal(
+        key,
+        cipher,
+        argon2,
+        plain.as_bytes(),
+        &manifest_aad(tag, name),
+    )?;
+    let path = dst_dir_path.join(super::manifest_name(name));
+    util::write_sync(&path, buf).await?;
+    debug!("To: {}", path.display());
+
+    Ok(())
+}
+
+async fn read_manifest(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    key: &AesKey,
+    cipher: CipherId,
+) -> Result<Manifest> {
+    let path = src_dir_path.join(super::manifest_name(name));
+    let buf = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    let plain = open(key, cipher, &buf, &manifest_aad(tag, name), &path)?;
+
+    toml::from_str(std::str::from_utf8(&plain)?)
+        .with_context(|| format!("Invalid manifest: {}", path.display()))
+}
+
+/// [restore::check_fragments] for the chunked layout.
+pub(super) fn check_chunks(src_dir_path: &Path, name: &str, info: &CryptInfo) -> Vec<String> {
+    let mut problems = Vec::new();
+    let Some(chunks) = &info.chunks else {
+        return problems;
+    };
+
+    let path = src_dir_path.join(super::manifest_name(name));
+    if !path.is_file() {
+        problems.push(format!("Manifest not found: {}", path.display()));
+    }
+    let chunks_path = chunks_path_of(src_dir_path);
+    for id in chunks.refs.iter() {
+        if id.len() != cryptutil::CHUNK_ID_SIZE * 2 {
+            problems.push(format!("Invalid chunk ID: {id}"));
+            continue;
+        }
+        let path = chunk_path(&chunks_path, id);
+        if !path.is_file() {
+            problems.push(format!("Chunk not found: {}", path.display()));
+        }
+    }
+
+    problems
+}
+
+/// [restore::decrypt_fragments] for the chunked layout.
+pub(super) async fn decrypt_chunks(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    info: &CryptInfo,
+    keys: &mut KeyCache,
+    mut fout: Option<&mut tokio::fs::File>,
+) -> Result<[u8; util::MD5LEN]> {
+    let (Some(cipher), Some(pk)) = (info.crypt.cipher(), info.crypt.passphrase_key()) else {
+        bail!("Chunked layout requires a passphrase key: {}", info.crypt);
+    };
+    let key = keys.data_key(&pk.argon2, pk.wrapped_key.as_ref())?;
+    let id_key = cryptutil::chunk_id_key(&key);
+    let chunks_path = chunks_path_of(src_dir_path);
+
+    let manifest = read_manifest(src_dir_path, tag, name, &key, cipher).await?;
+    let refs = info.chunks.as_ref().map(|chunks| &chunks.refs);
+    ensure!(
+        Some(&manifest.refs()) == refs,
+        "Chunk references unmatch: {}",
+        src_dir_path.display()
+    );
+
+    let mut hasher = Md5::new();
+    let mut total_size = 0u64;
+    for (idx, ent) in manifest.chunks.iter().enumerate() {
+        let data = read_chunk(&chunks_path, &ent.id, &key, &id_key, cipher)
+            .await
+            .with_context(|| format!("Chunk {idx}"))?;
+        ensure!(
+            data.len() as u64 == ent.size,
+            "Chunk {idx}: size unmatch (expected {}, actual {})",
+            ent.size,
+            data.len()
+        );
+        total_size += ent.size;
+
+        hasher.update(&data);
+        if let Some(fout) = &mut fout {
+            fout.write_all(&data).await?;
+        }
+    }
+    ensure!(
+        total_size == info.total_size,
+        "Total size unmatch (expected {}, actual {total_size})",
+        info.total_size
+    );
+    if let Some(fout) = &mut fout {
+        fout.flush().await?;
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Count references from crypt/\*/\*/metadata.toml.
+///
+/// Versions in `excluded` (crypt dirs) are not counted.
+/// Error if any metadata cannot be read (nothing should be removed then).
+fn chunk_refcounts(
+    crypt_path: &Path,
+    excluded: &BTreeSet<PathBuf>,
+) -> Result<BTreeMap<String, usize>> {
+    let mut refcounts = BTreeMap::new();
+    for path in restore::crypt_dirs(crypt_path)? {
+        if excluded.contains(&path) {
+            continue;
+        }
+        let info = restore::read_crypt_info(&path)?;
+        for id in info.chunks.iter().flat_map(|chunks| chunks.refs.iter()) {
+            *refcounts.entry(id.clone()).or_default() += 1;
+        }
+    }
+
+    Ok(refcounts)
+}
+
+/// Remove chunks in crypt/chunks/ which no metadata refers to.
+///
+/// excluded: crypt dirs of the versions to be removed (for dry run)
+/// Return (count, bytes) of removed (or to be removed if dry_run) chunks.
+pub(super) fn remove_unreferenced(
+    crypt_path: &Path,
+    excluded: &BTreeSet<PathBuf>,
+    dry_run: bool,
+) -> Result<(usize, u64)> {
+    let chunks_path = crypt_path.join(super::DIRNAME_CHUNKS);
+    if !chunks_path.is_dir() {
+        return Ok((0, 0));
+    }
+    let refcounts = chunk_refcounts(crypt_path, excluded)?;
+    info!("Referenced chunks: {}", refcounts.len());
+
+    let mut removed = 0;
+    let mut removed_size = 0;
+    for entry in chunks_path
+        .read_dir()
+        .with_context(|| format!("Cannot read dir: {}", chunks_path.display()))?
+    {
+        let dir_path = entry?.path();
+        if !dir_path.is_dir() {
+            continue;
+        }
+        for entry in dir_path.read_dir()? {
+            let path = entry?.path();
+            let Some(id) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            // tmp: left by an interrupted run
+            let is_tmp = path.extension().is_some_and(|ext| ext == TMPEXT);
+            if !is_tmp && refcounts.contains_key(id) {
+                continue;
+            }
+
+            let size = path.metadata()?.len();
+            if dry_run {
+                info!("Remove (dry run): {}", path.display());
+            } else {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => debug!("Delete OK: {}", path.display()),
+                    Err(err) if err.kind() == ErrorKind::NotFound => {
+                        warn!("Already deleted: {}", path.display());
+                        continue;
+                    }
+                    Err(err) => {
+                        return Err(err)
+                            .with_context(|| format!("Delete failed: {}", path.display()))
+                    }
+                }
+            }
+            removed += 1;
+            removed_size += size;
+        }
+    }
+
+    Ok((removed, removed_size))
+}
@@ -0,0 +1,501 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::Local;
+use getopts::Options;
+use log::{info, warn};
+
+use super::{Aes128GcmArgon2Param, Config, Secrets};
+use crate::cryptutil::{self, AesKey};
+use crate::util;
+
+fn process_list_backup(dirpath: &Path) -> Result<()> {
+    let _lock = super::lock_config(dirpath)?;
+
+    let names = super::list_config_backups(dirpath)?;
+    for name in names.iter() {
+        println!("{name}");
+    }
+    info!("{} backup(s)", names.len());
+
+    Ok(())
+}
+
+/// Replace config.toml with a backup (the latest if name is None).
+///
+/// The current config.toml is also backed up before replacing,
+/// and it does not need to be valid.
+fn process_restore_backup(dirpath: &Path, name: Option<&str>) -> Result<()> {
+    let _lock = super::lock_config(dirpath)?;
+    let tomlpath = dirpath.join(super::CONFIG_FILE_NAME);
+
+    let names = super::list_config_backups(dirpath)?;
+    let name = match name {
+        Some(name) => {
+            ensure!(names.iter().any(|s| s == name), "Backup not found: {name}");
+            name
+        }
+        None => match names.last() {
+            Some(name) => name.as_str(),
+            None => bail!("No backup found"),
+        },
+    };
+    let bakpath = dirpath.join(name);
+
+    // check format (an old version is allowed and can be migrated later)
+    let toml = std::fs::read_to_string(&bakpath)
+        .with_context(|| format!("Cannot read {}", bakpath.display()))?;
+    let table: toml::Table =
+        toml::from_str(&toml).with_context(|| format!("Cannot parse {}", bakpath.display()))?;
+    let version = super::config_version(&table)?;
+    ensure!(
+        version <= super::CONFIG_VERSION,
+        "Config version {version} is newer than supported ({})",
+        super::CONFIG_VERSION
+    );
+
+    if tomlpath.exists() {
+        super::backup_config(dirpath)?;
+    }
+    util::write_atomic(&tomlpath, toml)
+        .with_context(|| format!("Cannot write {}", tomlpath.display()))?;
+    info!("Restore OK: {name}");
+
+    Ok(())
+}
+
+/// Upgrade a config table from version N to N + 1.
+///
+/// Files in dirpath may be created if needed (must not be modified if dry_run).
+type Migration = fn(&Path, bool, toml::Table) -> Result<toml::Table>;
+
+/// MIGRATIONS\[i\]: version i + 1 to i + 2
+const MIGRATIONS: &[Migration] = &[migrate_v1_to_v2];
+const _: () = assert!(MIGRATIONS.len() + 1 == super::CONFIG_VERSION as usize);
+
+/// Remove the raw key in a version 1 table (crypt.Aes128GcmArgon2.key).
+fn remove_v1_key(table: &mut toml::Table) -> Option<toml::Value> {
+    table
+        .get_mut("crypt")
+        .and_then(|crypt| crypt.get_mut("Aes128GcmArgon2"))
+        .and_then(|aes| aes.as_table_mut())
+        .and_then(|aes| aes.remove("key"))
+}
+
+/// Move the derived key from config.toml into the secrets file
+/// and keep only the key-check value.
+fn migrate_v1_to_v2(dirpath: &Path, dry_run: bool, mut table: toml::Table) -> Result<toml::Table> {
+    let Some(key) = remove_v1_key(&mut table) else {
+        return Ok(table);
+    };
+    let aes = table["crypt"]["Aes128GcmArgon2"].as_table_mut().unwrap();
+
+    let key: AesKey = key.try_into().context("Invalid config: key")?;
+    let argon2: Aes128GcmArgon2Param = aes
+        .get("argon2")
+        .cloned()
+        .ok_or_else(|| anyhow!("Invalid config: argon2 not found"))?
+        .try_into()
+        .context("Invalid config: argon2")?;
+    aes.insert(
+        "key_check".to_string(),
+        toml::Value::try_from(cryptutil::aeskey_check(&key))?,
+    );
+
+    if dry_run {
+        info!("Move the key into {} (dry run)", super::SECRETS_FILE_NAME);
+    } else {
+        super::write_secrets(dirpath, Some(&Secrets { argon2, key }))?;
+        info!("Move the key into {}", super::SECRETS_FILE_NAME);
+    }
+
+    Ok(table)
+}
+
+/// Apply migrations step by step and return the latest version table.
+fn migrate_table(
+    dirpath: &Path,
+    dry_run: bool,
+    mut table: toml::Table,
+    migrations: &[Migration],
+) -> Result<toml::Table> {
+    let latest = migrations.len() as u32 + 1;
+    let from = super::config_version(&table)?;
+    ensure!(from >= 1, "Invalid config version: {from}");
+    ensure!(
+        from <= latest,
+        "Config version {from} is newer than supported ({latest}), update bkupman"
+    );
+
+    for (i, migration) in migrations.iter().enumerate().skip(from as usize - 1) {
+        let version = i as u32 + 1;
+        info!("Migrate: version {} => {}", version, version + 1);
+        table = migration(dirpath, dry_run, table)
+            .with_context(|| format!("Migration failed: version {version}"))?;
+
+        let system = table
+            .entry("system")
+            .or_insert_with(|| toml::Table::new().into())
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Invalid config: system"))?;
+        system.insert("version".to_string(), i64::from(version + 1).into());
+    }
+
+    Ok(table)
+}
+
+/// Remove the raw key from saved copies of config.toml
+/// (config.toml.bak.* and config.toml.v*.*) and make them private.
+///
+/// A file which cannot be parsed is made private only.
+fn scrub_saved_configs(dirpath: &Path) -> Result<()> {
+    let orig_prefix = format!("{}.v", super::CONFIG_FILE_NAME);
+    for entry in dirpath.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            continue;
+        };
+        if !(name.starts_with(super::CONFIG_BACKUP_PREFIX) || name.starts_with(&orig_prefix))
+            || name.ends_with(".tmp")
+            || !path.is_file()
+        {
+            continue;
+        }
+
+        let table = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|toml| toml::from_str::<toml::Table>(&toml).ok());
+        match table {
+            Some(mut table) => {
+                if remove_v1_key(&mut table).is_some() {
+                    util::write_atomic_private(&path, toml::to_string(&table)?)
+                        .with_context(|| format!("Cannot write {}", path.display()))?;
+                    info!("Remove the key: {name}");
+                }
+            }
+            None => warn!("Cannot parse {name}, make it private only"),
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Cannot change permission: {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Upgrade config.toml to the current version.
+///
+/// The original is saved as config.toml.v<version>.<timestamp> (without the raw key).
+fn process_migrate(dirpath: &Path, dry_run: bool) -> Result<()> {
+    let _lock = super::lock_config(dirpath)?;
+    let tomlpath = dirpath.join(super::CONFIG_FILE_NAME);
+
+    let old_toml = std::fs::read_to_string(&tomlpath)
+        .with_context(|| format!("Cannot read {}", tomlpath.display()))?;
+    let table: toml::Table = toml::from_str(&old_toml)
+        .with_context(|| format!("Cannot parse {}", tomlpath.display()))?;
+    let from = super::config_version(&table)?;
+    if from == super::CONFIG_VERSION {
+        info!("Already the latest version: {from}");
+        // saved by an older version of migrate
+        if !dry_run {
+            scrub_saved_configs(dirpath)?;
+        }
+        return Ok(());
+    }
+
+    let table = migrate_table(dirpath, dry_run, table, MIGRATIONS)?;
+    // normalize by the current format
+    let config: Config = toml::from_str(&toml::to_string(&table)?)
+        .context("Migrated config does not match the current format")?;
+    let new_toml = toml::to_string(&config).unwrap();
+
+    if dry_run {
+        print!("{}", util::line_diff(&old_toml, &new_toml));
+        return Ok(());
+    }
+
+    // the raw key has been moved into the secrets file
+    let mut orig_table: toml::Table = toml::from_str(&old_toml)?;
+    remove_v1_key(&mut orig_table);
+    let timestamp = Local::now().format("%Y%m%d%H%M%S");
+    let origpath = dirpath.join(format!("{}.v{from}.{timestamp}", super::CONFIG_FILE_NAME));
+    util::write_atomic_private(&origpath, toml::to_string(&orig_table)?)
+        .with_context(|| format!("Cannot write {}", origpath.display()))?;
+    info!("Write OK: {}", origpath.display());
+    scrub_saved_configs(dirpath)?;
+
+    util::write_atomic(&tomlpath, new_toml)
+        .with_context(|| format!("Cannot write {}", tomlpath.display()))?;
+    info!("Migrate OK: version {from} => {}", super::CONFIG_VERSION);
+
+    Ok(())
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str = "Manage config file.
+
+Actions:
+    migrate [--dry-run]
+        Upgrade config.toml to the current version (print diff only if --dry-run)
+    list-backup
+        List config.toml.bak.* (oldest first)
+    restore-backup [NAME]
+        Replace config.toml with the backup (the latest by default)";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optflag("n", "dry-run", "Do not write anything (migrate)");
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!(
+            "{}",
+            util::create_help(cmd, DESC, &opts, Some("ACTION [ARGS...]"))
+        );
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let dry_run = matches.opt_present("n");
+
+    let Some((action, action_args)) = matches.free.split_first() else {
+        bail!("ACTION is required");
+    };
+    match action.as_str() {
+        "migrate" => {
+            ensure!(action_args.is_empty(), "Too many arguments");
+            process_migrate(basedir, dry_run)
+        }
+        "list-backup" => {
+            ensure!(action_args.is_empty(), "Too many arguments");
+            process_list_backup(basedir)
+        }
+        "restore-backup" => {
+            ensure!(action_args.len() <= 1, "Too many arguments");
+            process_restore_backup(basedir, action_args.first().map(|s| s.as_str()))
+        }
+        _ => bail!("Unknown action: {action}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CryptType, PassphraseKey, CONFIG_BACKUP_COUNT, CONFIG_FILE_NAME};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_backup_and_restore() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tomlpath = dirpath.join(CONFIG_FILE_NAME);
+        std::fs::write(&tomlpath, toml::to_string(&Config::default())?)?;
+
+        assert!(process_restore_backup(dirpath, None).is_err());
+
+        for i in 0..(CONFIG_BACKUP_COUNT as u32 + 2) {
+            let mut config = Config::default();
+            config.retention.last = i;
+            super::super::save_config(dirpath, &config)?;
+            // timestamp resolution
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let names = super::super::list_config_backups(dirpath)?;
+        assert_eq!(names.len(), CONFIG_BACKUP_COUNT);
+        assert!(!dirpath.join("config.toml.tmp").exists());
+
+        // broken config.toml can be restored
+        std::fs::write(&tomlpath, "broken")?;
+        process_restore_backup(dirpath, None)?;
+        let config = super::super::read_config(&tomlpath)?;
+        assert_eq!(config.retention.last, CONFIG_BACKUP_COUNT as u32);
+
+        // names[0] has been rotated out by the backup of the broken one
+        assert!(process_restore_backup(dirpath, Some(&names[0])).is_err());
+        process_restore_backup(dirpath, Some(&names[2]))?;
+        let config = super::super::read_config(&tomlpath)?;
+        assert_eq!(config.retention.last, 3);
+
+        assert!(process_restore_backup(dirpath, Some("../config.toml")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_same_millisecond() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tomlpath = dirpath.join(CONFIG_FILE_NAME);
+        std::fs::write(&tomlpath, toml::to_string(&Config::default())?)?;
+
+        // no sleep: no backup is overwritten
+        for i in 1..=CONFIG_BACKUP_COUNT as u32 {
+            let mut config = Config::default();
+            config.retention.last = i;
+            super::super::save_config(dirpath, &config)?;
+        }
+        let names = super::super::list_config_backups(dirpath)?;
+        assert_eq!(names.len(), CONFIG_BACKUP_COUNT);
+        // oldest first
+        for (i, name) in names.iter().enumerate() {
+            let config = super::super::read_config(&dirpath.join(name))?;
+            assert_eq!(config.retention.last, i as u32);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_table() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        fn v1_to_v2(_: &Path, _: bool, mut table: toml::Table) -> Result<toml::Table> {
+            table.insert("v2".to_string(), true.into());
+            Ok(table)
+        }
+        fn v2_to_v3(_: &Path, _: bool, mut table: toml::Table) -> Result<toml::Table> {
+            table.insert("v3".to_string(), true.into());
+            Ok(table)
+        }
+        let migrations: &[Migration] = &[v1_to_v2, v2_to_v3];
+
+        // version 1 (system is missing)
+        let table = migrate_table(dirpath, false, toml::Table::new(), migrations)?;
+        assert_eq!(super::super::config_version(&table)?, 3);
+        assert!(table.contains_key("v2") && table.contains_key("v3"));
+
+        // version 2
+        let table: toml::Table = toml::from_str("[system]\nversion = 2\n")?;
+        let table = migrate_table(dirpath, false, table, migrations)?;
+        assert_eq!(super::super::config_version(&table)?, 3);
+        assert!(!table.contains_key("v2") && table.contains_key("v3"));
+
+        // newer
+        let table: toml::Table = toml::from_str("[system]\nversion = 4\n")?;
+        assert!(migrate_table(dirpath, false, table, migrations).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_config_version() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let tomlpath = tmpdir.path().join(CONFIG_FILE_NAME);
+
+        std::fs::write(&tomlpath, toml::to_string(&Config::default())?)?;
+        super::super::read_config(&tomlpath)?;
+
+        let mut config = Config::default();
+        config.system.version = super::super::CONFIG_VERSION + 1;
+        std::fs::write(&tomlpath, toml::to_string(&config)?)?;
+        assert!(super::super::read_config(&tomlpath).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_v1_to_v2() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        let key: AesKey = cryptutil::generate_random();
+        let mut table: toml::Table = toml::from_str(
+            "[system]\nversion = 1\nupdated = \"\"\n\
+             [crypt.Aes128GcmArgon2.argon2]\nm_cost = 1\nt_cost = 2\np_cost = 3\n",
+        )?;
+        let mut aes = table["crypt"]["Aes128GcmArgon2"]
+            .as_table()
+            .unwrap()
+            .clone();
+        aes["argon2"]
+            .as_table_mut()
+            .unwrap()
+            .insert("salt".to_string(), toml::Value::try_from([0u8; 16])?);
+        aes.insert("key".to_string(), toml::Value::try_from(key)?);
+        table["crypt"]
+            .as_table_mut()
+            .unwrap()
+            .insert("Aes128GcmArgon2".to_string(), aes.into());
+
+        // nothing is written
+        let migrated = migrate_table(dirpath, true, table.clone(), MIGRATIONS)?;
+        assert!(super::super::read_secrets(dirpath)?.is_none());
+
+        let migrated2 = migrate_table(dirpath, false, table, MIGRATIONS)?;
+        assert_eq!(migrated, migrated2);
+        let config: Config = toml::from_str(&toml::to_string(&migrated)?)?;
+        assert_eq!(config.system.version, 2);
+        let CryptType::Aes128GcmArgon2(PassphraseKey {
+            key_check, argon2, ..
+        }) = config.crypt
+        else {
+            panic!();
+        };
+        assert_eq!(key_check, Some(cryptutil::aeskey_check(&key)));
+
+        let secrets = super::super::read_secrets(dirpath)?.unwrap();
+        assert_eq!(secrets.key, key);
+        assert_eq!(secrets.argon2, argon2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dirpath.join(super::super::SECRETS_FILE_NAME);
+            assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o600);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_removes_key() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        let key: AesKey = cryptutil::generate_random();
+        let v1_toml = format!(
+            "[system]\nversion = 1\nupdated = \"\"\n\
+             [crypt.Aes128GcmArgon2]\nkey = {}\n\
+             [crypt.Aes128GcmArgon2.argon2]\nsalt = {}\nm_cost = 1\nt_cost = 2\np_cost = 3\n",
+            toml::Value::try_from(key)?,
+            toml::Value::try_from([0u8; 16])?,
+        );
+        std::fs::write(dirpath.join(CONFIG_FILE_NAME), &v1_toml)?;
+        // rotated before migration
+        std::fs::write(dirpath.join("config.toml.bak.20240101000000000"), &v1_toml)?;
+        std::fs::write(dirpath.join("config.toml.bak.20240101000000001"), "broken")?;
+
+        process_migrate(dirpath, false)?;
+
+        let key_str = toml::Value::try_from(key)?.to_string();
+        let mut checked = 0;
+        for entry in dirpath.read_dir()? {
+            let path = entry?.path();
+            // the only place of the key
+            if path.ends_with(super::super::SECRETS_FILE_NAME) {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)?;
+            assert!(!content.contains(&key_str), "{}", path.display());
+            checked += 1;
+        }
+        // config.toml, config.toml.v1.*, 2 backups (and the lock)
+        assert!(checked >= 4);
+        assert_eq!(super::super::read_secrets(dirpath)?.unwrap().key, key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for name in super::super::list_config_backups(dirpath)? {
+                let mode = dirpath.join(name).metadata()?.permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+
+        Ok(())
+    }
+}
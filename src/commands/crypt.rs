@@ -3,15 +3,18 @@ use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail, Context, Result};
-use bytes::{BufMut, BytesMut};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use fastcdc::v2020::StreamCDC;
 use getopts::Options;
-use log::{debug, info};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
 use tokio::io::AsyncWriteExt;
 use tokio::runtime::Runtime;
 
-use super::{Config, RepositoryFile};
-use crate::commands::{Aes128GcmArgon2Param, CryptInfo, CryptType};
+use super::chunk;
+use super::restore::{self, KeyCache};
+use super::{ChunkInfo, Compression, Config, PassphraseSource, RepositoryFile};
+use crate::commands::{CryptInfo, CryptType, PassphraseKey};
 use crate::cryptutil::AesKey;
 use crate::{cryptutil, util};
 
@@ -23,32 +26,213 @@ struct ProcessStat {
     error: AtomicU32,
 }*/
 
+const STAGING_OLD_SUFFIX: &str = ".old";
+
+/// Extensions of already compressed formats (the last part, lower case)
+const COMPRESSED_EXTS: &[&str] = &[
+    "7z", "br", "bz2", "gz", "jpeg", "jpg", "lz", "lz4", "lzma", "mkv", "mp3", "mp4", "png", "rar",
+    "tbz", "tgz", "txz", "webm", "webp", "xz", "zip", "zst",
+];
+/// The first part of a file to test compression
+const COMPRESS_SAMPLE_SIZE: usize = 1024 * 1024;
+/// Not compressed unless the sample shrinks below this ratio
+const COMPRESS_MAX_RATIO: f64 = 0.9;
+
 struct TaskParam {
     ctype: CryptType,
-    fragment_size: NonZeroU64,
+    /// Data key, unwrapped before starting tasks if needed
+    key: Option<AesKey>,
     repo_path: PathBuf,
     crypt_path: PathBuf,
+    /// Shared by all tasks
+    limit: util::TaskLimit,
+}
+
+/// How to store each fragment of a file.
+#[derive(Debug, Clone, Copy)]
+struct FragmentParam {
+    size: NonZeroU64,
+    compression: Option<Compression>,
+}
+
+/// Per-tag settings (tag config or command line).
+#[derive(Debug, Clone, Copy)]
+struct TagParam {
+    fragment_size: NonZeroU64,
+    /// 0: no compression
+    zstd_level: i32,
+    /// (data, parity) fragments per group
+    parity: Option<(usize, usize)>,
+    /// Content-defined chunks in crypt/chunks/ instead of fragments
+    chunked: bool,
+    /// Encrypt the newest N versions only (None: all)
+    last: Option<u32>,
+}
+
+/// Estimated peak buffer memory of a task (for the memory budget).
+fn task_memory(tag_param: &TagParam) -> u64 {
+    let data = if tag_param.chunked {
+        // CDC read buffer, chunk, encoded and sealed
+        4 * chunk::CHUNK_MAX as u64
+    } else {
+        // read buffer and ciphertext (+ compressed)
+        let buffers = if tag_param.zstd_level == 0 { 2 } else { 3 };
+        buffers * tag_param.fragment_size.get()
+    };
+    // one stripe of each shard
+    let parity = tag_param.parity.map_or(0, |(data_shards, parity_shards)| {
+        ((data_shards + parity_shards) * super::repair::STRIPE_SIZE) as u64
+    });
+
+    data.max(parity)
 }
 
+/// Decide whether to compress by the extension and a sample.
+///
+/// zstd_level 0 means no compression.
+async fn choose_compression(
+    src_file_path: &Path,
+    name: &str,
+    zstd_level: i32,
+) -> Result<Option<Compression>> {
+    if zstd_level == 0 {
+        return Ok(None);
+    }
+
+    let ext = super::split_filename(name)
+        .map(|(_, _, ext)| ext)
+        .unwrap_or_default();
+    let last = ext.rsplit('.').next().unwrap_or_default().to_lowercase();
+    if COMPRESSED_EXTS.contains(&last.as_str()) {
+        info!("Skip compression (already compressed): {name}");
+        return Ok(None);
+    }
+
+    let mut fin = tokio::fs::File::open(src_file_path).await?;
+    let mut sample = vec![0u8; COMPRESS_SAMPLE_SIZE];
+    let size = util::read_fully(&mut fin, &mut sample).await?;
+    let sample = &sample[..size];
+    let compression = Compression::Zstd { level: zstd_level };
+    let compressed = compression.compress(sample)?;
+    if compressed.len() as f64 >= sample.len() as f64 * COMPRESS_MAX_RATIO {
+        info!(
+            "Skip compression (sample {} -> {} bytes): {name}",
+            sample.len(),
+            compressed.len()
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(compression))
+}
+
+/// Return crypt metadata to be saved.
 async fn process_file_plain(
-    _param: Arc<TaskParam>,
-    _tag: String,
-    _rf: RepositoryFile,
-) -> Result<()> {
-    Ok(())
+    src_file_path: &Path,
+    dst_dir_path: &Path,
+    rf: RepositoryFile,
+    fragment: FragmentParam,
+) -> Result<CryptInfo> {
+    let fragment_size = fragment.size;
+    // source file
+    let mut fin = tokio::fs::File::open(src_file_path).await?;
+
+    let bufsize = fragment_size.get() as usize;
+    let mut rawbuf = vec![0u8; bufsize];
+    let mut total_size = 0u64;
+    let mut idx = 0u64;
+    loop {
+        let rsize = util::read_fully(&mut fin, &mut rawbuf).await?;
+        if rsize == 0 {
+            break;
+        }
+        total_size += rsize as u64;
+        // no encryption (and no tampering detection),
+        // so save checksum of each fragment (as stored)
+        let compression = fragment.compression;
+        let (buf, compressed, md5str) = util::blocking(move || {
+            let plain = &rawbuf[..rsize];
+            let compressed = compression.map(|c| c.compress(plain)).transpose()?;
+            let stored = compressed.as_deref().unwrap_or(plain);
+            let md5str = util::md5_to_str(&Md5::digest(stored));
+            Ok((rawbuf, compressed, md5str))
+        })
+        .await?;
+        rawbuf = buf;
+        let stored = compressed.as_deref().unwrap_or(&rawbuf[..rsize]);
+
+        // fragment file name
+        let dst_path = dst_dir_path.join(super::fragment_name(&rf.name, idx));
+        util::write_sync(&dst_path, stored).await?;
+        debug!("To: {}", dst_path.display());
+
+        let dst_md5_path = dst_dir_path.join(super::fragment_md5_name(&rf.name, idx));
+        util::write_sync(&dst_md5_path, md5str).await?;
+
+        idx += 1;
+    }
+
+    // crypt matadata
+    let info = CryptInfo {
+        crypt: CryptType::PlainText,
+        total_size,
+        fragment_size,
+        aad: false,
+        header_version: 0,
+        compression: fragment.compression,
+        parity: None,
+        chunks: None,
+    };
+
+    let total_count: u64 = idx;
+    info!(
+        "Complete: {} ({} files, {} bytes)",
+        dst_dir_path.display(),
+        total_count,
+        total_size
+    );
+
+    Ok(info)
 }
 
-async fn process_file_aes(
+/// Return crypt metadata to be saved.
+async fn process_file_aead(
     src_file_path: &Path,
     dst_dir_path: &Path,
-    dst_info_path: &Path,
+    tag: &str,
     rf: RepositoryFile,
-    fragment_size: NonZeroU64,
-    key: AesKey,
-    argon2: Aes128GcmArgon2Param,
-) -> Result<()> {
+    fragment: FragmentParam,
+    param: &TaskParam,
+) -> Result<CryptInfo> {
+    let fragment_size = fragment.size;
+    let Some(cipher) = param.ctype.cipher() else {
+        bail!("Not encrypted: {}", param.ctype);
+    };
+    // metadata to be saved (the wrapped key is needed to decrypt)
+    let (key, info_crypt) = match &param.ctype {
+        CryptType::X25519(rk) => {
+            // a new data key for each file, forgotten after encryption
+            let key: AesKey = cryptutil::generate_random();
+            (key, CryptType::X25519(rk.seal(&key)?))
+        }
+        ctype => {
+            let key = param
+                .key
+                .ok_or_else(|| anyhow!("Encryption key is empty"))?;
+            // don't save the key check
+            let pk = ctype.passphrase_key().unwrap();
+            let pk = PassphraseKey {
+                key_check: None,
+                ..pk.clone()
+            };
+            (key, ctype.with_key(pk))
+        }
+    };
+
     // source file
     let mut fin = tokio::fs::File::open(src_file_path).await?;
+    // fragment count is authenticated in each fragment
+    let count = fin.metadata().await?.len().div_ceil(fragment_size.get());
 
     let bufsize = fragment_size.get() as usize;
     let mut rawbuf = vec![0u8; bufsize];
@@ -59,51 +243,74 @@ async fn process_file_aes(
         if rsize == 0 {
             break;
         }
-        let rawbuf = &rawbuf[..rsize];
         total_size += rsize as u64;
+        ensure!(
+            idx < count,
+            "File size changed: {}",
+            src_file_path.display()
+        );
 
+        // compress (encrypted data is incompressible)
         // encrypt
-        // use saved key
-        // nonce: 96 bit = 12 byte, must generate new one every time
-        let (nonce, encbuf) = cryptutil::encrypt_aes256gcm(&key, rawbuf)?;
+        // use the data key
+        // nonce: AES 12 byte, XChaCha20 24 byte, must generate new one every time
+        let aad = super::fragment_aad(tag, &rf.name, idx, count);
+        let compression = fragment.compression;
+        let (buf, plain_size, nonce, encbuf) = util::blocking(move || {
+            let plain = &rawbuf[..rsize];
+            let compressed = compression.map(|c| c.compress(plain)).transpose()?;
+            let plain = compressed.as_deref().unwrap_or(plain);
+            let (nonce, encbuf) = cipher.encrypt(&key, plain, &aad)?;
+            let plain_size = plain.len();
+            Ok((rawbuf, plain_size, nonce, encbuf))
+        })
+        .await?;
+        rawbuf = buf;
 
         // fragment file name
-        let dst_path = dst_dir_path.join(&format!("{}.{:0>6}", rf.name, idx));
+        let dst_path = dst_dir_path.join(super::fragment_name(&rf.name, idx));
         let mut fout = tokio::fs::File::create(&dst_path).await?;
         debug!("To: {}", dst_path.display());
 
-        // Argon2 salt:16, m:4, t:4, p:4
-        // aes256-gcm nonce:12
+        // header (see cryptutil::FragmentHeader)
         // ciphertext (+ tag:16)
-        let mut header_buf = BytesMut::with_capacity(64);
-        header_buf.put(&argon2.salt[..]);
-        header_buf.put_u32_le(argon2.m_cost);
-        header_buf.put_u32_le(argon2.t_cost);
-        header_buf.put_u32_le(argon2.p_cost);
-        header_buf.put(&nonce[..]);
+        let header = match info_crypt.passphrase_key() {
+            Some(pk) => pk.argon2.to_header(cipher, idx, nonce),
+            None => cryptutil::FragmentHeader::new_x25519(cipher, idx, nonce),
+        };
+        let header_buf = header.to_bytes();
 
         debug!(
             "plain: {}, header: {}, crypted: {}",
-            rawbuf.len(),
+            plain_size,
             header_buf.len(),
             encbuf.len()
         );
 
         fout.write_all(&header_buf).await?;
         fout.write_all(&encbuf).await?;
+        fout.sync_all().await?;
         drop(fout);
 
         idx += 1;
     }
+    ensure!(
+        idx == count,
+        "File size changed: {}",
+        src_file_path.display()
+    );
 
-    // save crypt matadata
+    // crypt matadata
     let info = CryptInfo {
-        // don't save the AES key
-        crypt: CryptType::Aes128GcmArgon2 { key: None, argon2 },
+        crypt: info_crypt,
         total_size,
         fragment_size,
+        aad: true,
+        header_version: cryptutil::FRAGMENT_VERSION,
+        compression: fragment.compression,
+        parity: None,
+        chunks: None,
     };
-    tokio::fs::write(&dst_info_path, toml::to_string(&info)?).await?;
 
     let total_count: u64 = idx;
     info!(
@@ -113,13 +320,227 @@ async fn process_file_aes(
         total_size
     );
 
+    Ok(info)
+}
+
+/// Split by content-defined chunking and store new chunks in crypt/chunks/.
+///
+/// Chunks already stored (by any tag or version) are not written again.
+/// Return crypt metadata to be saved.
+async fn process_file_chunked(
+    src_file_path: &Path,
+    dst_dir_path: &Path,
+    tag: &str,
+    rf: RepositoryFile,
+    compression: Option<Compression>,
+    param: &TaskParam,
+) -> Result<CryptInfo> {
+    let (Some(cipher), Some(pk)) = (param.ctype.cipher(), param.ctype.passphrase_key()) else {
+        bail!("Chunked layout requires a passphrase key: {}", param.ctype);
+    };
+    let key = param
+        .key
+        .ok_or_else(|| anyhow!("Encryption key is empty"))?;
+    // don't save the key check
+    let pk = PassphraseKey {
+        key_check: None,
+        ..pk.clone()
+    };
+    let id_key = cryptutil::chunk_id_key(&key);
+    let chunks_path = param.crypt_path.join(super::DIRNAME_CHUNKS);
+
+    // source file
+    let fin = std::fs::File::open(src_file_path)?;
+
+    let mut manifest = chunk::Manifest::default();
+    let mut total_size = 0u64;
+    let mut added = 0;
+    let mut chunker = StreamCDC::new(fin, chunk::CHUNK_MIN, chunk::CHUNK_AVG, chunk::CHUNK_MAX);
+    loop {
+        // read and hash on the blocking thread pool
+        let (rest, next) = util::blocking(move || {
+            let next = match chunker.next().transpose()? {
+                Some(c) => Some((
+                    chunk::id_to_str(&cryptutil::chunk_id(&id_key, &c.data)),
+                    c.data,
+                )),
+                None => None,
+            };
+            Ok((chunker, next))
+        })
+        .await?;
+        chunker = rest;
+        let Some((id, data)) = next else {
+            break;
+        };
+        let size = data.len() as u64;
+        let written = chunk::write_chunk(
+            &chunks_path,
+            &id,
+            data,
+            &key,
+            cipher,
+            &pk.argon2,
+            compression,
+        )
+        .await?;
+        if written {
+            added += 1;
+        }
+        total_size += size;
+        manifest.push(id, size);
+    }
+    chunk::write_manifest(
+        dst_dir_path,
+        tag,
+        &rf.name,
+        &manifest,
+        &key,
+        cipher,
+        &pk.argon2,
+    )
+    .await?;
+
+    // crypt matadata
+    let info = CryptInfo {
+        crypt: param.ctype.with_key(pk),
+        total_size,
+        fragment_size: NonZeroU64::new(chunk::CHUNK_MAX as u64).unwrap(),
+        aad: true,
+        header_version: cryptutil::FRAGMENT_VERSION,
+        compression,
+        parity: None,
+        chunks: Some(ChunkInfo {
+            refs: manifest.refs(),
+        }),
+    };
+
+    info!(
+        "Complete: {} ({} chunks, {} new, {} bytes)",
+        dst_dir_path.display(),
+        manifest.len(),
+        added,
+        total_size
+    );
+
+    Ok(info)
+}
+
+/// Replace crypt/tag/name with crypt/.staging/tag/name.
+///
+/// The old one is moved to crypt/.staging/tag/name.old before replacing,
+/// and then deleted.
+async fn commit_staging(crypt_path: &Path, tag: &str, name: &str) -> Result<()> {
+    let staging_path = crypt_path.join(super::DIRNAME_STAGING).join(tag);
+    let src_path = staging_path.join(name);
+    let tag_path = crypt_path.join(tag);
+    let dst_path = tag_path.join(name);
+    let old_path = staging_path.join(format!("{name}{STAGING_OLD_SUFFIX}"));
+
+    tokio::fs::create_dir_all(&tag_path)
+        .await
+        .with_context(|| format!("Mkdir failed: {}", tag_path.display()))?;
+
+    if tokio::fs::try_exists(&dst_path).await? {
+        tokio::fs::rename(&dst_path, &old_path)
+            .await
+            .with_context(|| format!("Rename failed: {}", dst_path.display()))?;
+    }
+    tokio::fs::rename(&src_path, &dst_path)
+        .await
+        .with_context(|| format!("Rename failed: {}", src_path.display()))?;
+    for path in [crypt_path, &tag_path, &staging_path] {
+        util::sync_dir_async(path).await?;
+    }
+    info!("Commit: {}", dst_path.display());
+
+    if tokio::fs::try_exists(&old_path).await? {
+        tokio::fs::remove_dir_all(&old_path)
+            .await
+            .with_context(|| format!("Rmdir failed: {}", old_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Clean up crypt/.staging left by an interrupted run.
+///
+/// If crypt/tag/name had been moved to crypt/.staging/tag/name.old but the new
+/// one had not been moved to crypt/tag/name yet, move it back.
+/// crypt/.staging/tag.old (old layout) is also moved back to crypt/tag.
+fn clean_staging(crypt_path: &Path) -> Result<()> {
+    let staging_path = crypt_path.join(super::DIRNAME_STAGING);
+    if !staging_path.exists() {
+        return Ok(());
+    }
+
+    for entry in staging_path.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if name.ends_with(STAGING_OLD_SUFFIX) {
+            clean_staging_entry(&path, crypt_path)?;
+        } else if path.is_dir() {
+            // crypt/.staging/tag/name
+            let tag_path = crypt_path.join(&*name);
+            for entry in path.read_dir()? {
+                clean_staging_entry(&entry?.path(), &tag_path)?;
+            }
+            std::fs::remove_dir(&path)
+                .with_context(|| format!("Rmdir failed: {}", path.display()))?;
+        } else {
+            clean_staging_entry(&path, crypt_path)?;
+        }
+    }
+    util::sync_dir(crypt_path)?;
+
     Ok(())
 }
 
-/// Return tag if succeeded
-async fn process_file(param: Arc<TaskParam>, tag: String, rf: RepositoryFile) -> Result<String> {
+/// Move "name.old" back to dst_dir_path/name if lost, or delete it.
+fn clean_staging_entry(path: &Path, dst_dir_path: &Path) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if let Some(name) = name.strip_suffix(STAGING_OLD_SUFFIX) {
+        let dst_path = dst_dir_path.join(name);
+        if !dst_path.exists() {
+            warn!("Interrupted run detected, move back: {}", path.display());
+            std::fs::create_dir_all(dst_dir_path)
+                .with_context(|| format!("Mkdir failed: {}", dst_dir_path.display()))?;
+            std::fs::rename(path, &dst_path)
+                .with_context(|| format!("Rename failed: {}", path.display()))?;
+            return Ok(());
+        }
+    }
+    warn!("Interrupted run detected, delete: {}", path.display());
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("Delete failed: {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Return (tag, name) if succeeded
+async fn process_file(
+    param: Arc<TaskParam>,
+    tag: String,
+    rf: RepositoryFile,
+    tag_param: TagParam,
+) -> Result<(String, String)> {
+    // wait for a job slot and the memory budget
+    let _permit = param.limit.acquire(task_memory(&tag_param)).await?;
+
     let src_file_path = param.repo_path.join(&tag).join(&rf.name);
-    let dst_dir_path = param.crypt_path.join(&tag);
+    // write into crypt/.staging/tag/name and then move to crypt/tag/name
+    let dst_dir_path = param
+        .crypt_path
+        .join(super::DIRNAME_STAGING)
+        .join(&tag)
+        .join(&rf.name);
     let dst_info_path = dst_dir_path.join(super::CRYPT_INFO_NAME);
 
     info!("Clean: {}", dst_dir_path.display());
@@ -139,50 +560,82 @@ async fn process_file(param: Arc<TaskParam>, tag: String, rf: RepositoryFile) ->
         src_file_path.display(),
         dst_dir_path.display()
     );
+    let fragment = FragmentParam {
+        size: tag_param.fragment_size,
+        compression: choose_compression(&src_file_path, &rf.name, tag_param.zstd_level).await?,
+    };
 
-    match &param.ctype {
-        CryptType::PlainText => process_file_plain(param, tag.clone(), rf).await?,
-        CryptType::Aes128GcmArgon2 { key, argon2 } => {
-            let key = key.ok_or_else(|| anyhow!("Encryption key is empty"))?;
-            process_file_aes(
+    let name = rf.name.clone();
+    let mut info = match &param.ctype {
+        _ if tag_param.chunked => {
+            process_file_chunked(
                 &src_file_path,
                 &dst_dir_path,
-                &dst_info_path,
+                &tag,
                 rf,
-                param.fragment_size,
-                key,
-                argon2.clone(),
+                fragment.compression,
+                &param,
             )
             .await?
         }
+        CryptType::PlainText => {
+            process_file_plain(&src_file_path, &dst_dir_path, rf, fragment).await?
+        }
+        CryptType::Aes128GcmArgon2(_)
+        | CryptType::XChaCha20Poly1305Argon2(_)
+        | CryptType::X25519(_) => {
+            process_file_aead(&src_file_path, &dst_dir_path, &tag, rf, fragment, &param).await?
+        }
+    };
+    // parity over the stored fragments (no key is needed to repair)
+    if let Some((data_shards, parity_shards)) = tag_param.parity {
+        let parity = super::repair::write_parity(
+            &dst_dir_path,
+            &name,
+            info.fragment_count(),
+            data_shards,
+            parity_shards,
+        )
+        .await?;
+        info!(
+            "Parity: {} ({} files)",
+            dst_dir_path.display(),
+            parity.parity_md5.len()
+        );
+        info.parity = Some(parity);
     }
+    util::write_sync(&dst_info_path, toml::to_string(&info)?).await?;
+
+    util::sync_dir_async(&dst_dir_path).await?;
+    commit_staging(&param.crypt_path, &tag, &name).await?;
 
-    Ok(tag)
+    Ok((tag, name))
 }
 
 async fn process_files(
     param: Arc<TaskParam>,
-    files: &[(String, RepositoryFile)],
-) -> (Result<()>, Vec<String>) {
+    files: &[(String, RepositoryFile, TagParam)],
+) -> (Result<()>, Vec<(String, String)>) {
     info!("{} files to be processed", files.len());
     let handles: Vec<_> = files
         .iter()
-        .map(|(tag, rf)| {
+        .map(|(tag, rf, tag_param)| {
             let param = Arc::clone(&param);
             let tag = tag.clone();
             let rf = rf.clone();
+            let tag_param = *tag_param;
             // create a task
-            tokio::spawn(async move { process_file(param, tag, rf).await })
+            tokio::spawn(async move { process_file(param, tag, rf, tag_param).await })
         })
         .collect();
 
-    let mut succeeded_tags = vec![];
+    let mut succeeded = vec![];
     let mut failed = 0;
     for h in handles {
         // JoinError happens only if cancel or panic
         match h.await.unwrap() {
-            Ok(tag) => {
-                succeeded_tags.push(tag);
+            Ok(file) => {
+                succeeded.push(file);
             }
             Err(err) => {
                 println!("{:#}", err);
@@ -190,7 +643,7 @@ async fn process_files(
             }
         }
     }
-    info!("Succeeded: {}", succeeded_tags.len());
+    info!("Succeeded: {}", succeeded.len());
     info!("Failed   : {}", failed);
 
     let res = if failed == 0 {
@@ -199,51 +652,93 @@ async fn process_files(
         Err(anyhow!("One or more errors occurred"))
     };
 
-    (res, succeeded_tags)
+    (res, succeeded)
 }
 
 fn process_crypt(
     dirpath: &Path,
     mut config: Config,
-    fragment_size: NonZeroU64,
+    defaults: TagParam,
+    source: PassphraseSource,
+    limit: util::TaskLimit,
+    recrypt_missing: bool,
 ) -> (Option<Config>, Result<()>) {
     let repo_path = dirpath.join(super::DIRNAME_REPO);
     let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
 
+    if let Err(err) = clean_staging(&crypt_path) {
+        return (None, Err(err));
+    }
+
+    // filter to pick up (newest N && no crypt data)
+    let mut files_wo_crypt = Vec::new();
+    for (tag, ents) in config.repository.entries.iter() {
+        // crypt data of old versions was overwritten in the old layout,
+        // so missing ones are processed again only if requested
+        let mut targets = ents
+            .iter()
+            .map(|rf| &rf.0)
+            .filter(|rf| {
+                !rf.crypt
+                    || (recrypt_missing
+                        && restore::find_crypt_dir(&crypt_path, tag, &rf.name).is_none())
+            })
+            .peekable();
+        if targets.peek().is_none() {
+            continue;
+        }
+        if !config.crypt_enabled_for(tag) {
+            info!("Skip (crypt disabled): {tag}");
+            continue;
+        }
+        if tag == super::DIRNAME_CHUNKS {
+            warn!("Skip (reserved name in crypt/): {tag}");
+            continue;
+        }
+        let tag_param = match tag_param(&config, tag, defaults) {
+            Ok(tag_param) => tag_param,
+            Err(err) => return (None, Err(err).context(format!("Invalid config: {tag}"))),
+        };
+        let last = tag_param.last.map_or(usize::MAX, |last| last as usize);
+        let newest: Vec<_> = ents.iter().take(last).map(|rf| &rf.0.name).collect();
+        for rf in targets.filter(|rf| newest.contains(&&rf.name)) {
+            files_wo_crypt.push((tag.to_string(), rf.clone(), tag_param));
+        }
+    }
+
+    // passphrase is needed if the key is not saved
+    let key = match config.crypt.passphrase_key() {
+        Some(PassphraseKey {
+            argon2,
+            wrapped_key,
+            ..
+        }) if !files_wo_crypt.is_empty() => {
+            match KeyCache::load(dirpath, &config, source)
+                .and_then(|mut keys| keys.data_key(argon2, wrapped_key.as_ref()))
+            {
+                Ok(key) => Some(key),
+                Err(err) => return (None, Err(err)),
+            }
+        }
+        _ => None,
+    };
     let param = Arc::new(TaskParam {
         ctype: config.crypt.clone(),
-        fragment_size,
+        key,
         repo_path,
         crypt_path,
+        limit,
     });
 
-    // filter to pick up (latest && no crypt data)
-    let latest_files_wo_crypt: Vec<_> = config
-        .repository
-        .entries
-        .iter()
-        .filter_map(|(k, v)| {
-            let latest = v.first();
-            if let Some(rf) = latest {
-                if !rf.0.crypt {
-                    Some((k.to_string(), rf.0.clone()))
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
-        .collect();
-
     let rt = Runtime::new().unwrap();
-    let (res, succeeded_tags) = rt.block_on(process_files(param, &latest_files_wo_crypt));
+    let (res, succeeded) = rt.block_on(process_files(param, &files_wo_crypt));
     drop(rt);
 
     // update toml
-    for tag in succeeded_tags.iter() {
+    for (tag, name) in succeeded.iter() {
         let ents = config.repository.entries.get_mut(tag).unwrap();
-        let mut rf = ents.pop_first().unwrap();
+        let mut rf = ents.iter().find(|rf| &rf.0.name == name).unwrap().clone();
+        ents.remove(&rf);
         rf.0.crypt = true;
         ents.insert(rf);
     }
@@ -252,8 +747,82 @@ fn process_crypt(
     (Some(config), res)
 }
 
-pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+/// Tag config overrides the command line.
+fn tag_param(config: &Config, tag: &str, defaults: TagParam) -> Result<TagParam> {
+    let Some(tc) = config.tag_config(tag) else {
+        return check_tag_param(config, defaults);
+    };
+
+    let fragment_size = match tc.fragment_size.as_deref() {
+        Some(s) => parse_fragment_size(s)?,
+        None => defaults.fragment_size,
+    };
+    let zstd_level = match tc.zstd_level {
+        Some(level) => check_zstd_level(level)?,
+        None => defaults.zstd_level,
+    };
+    let parity = match tc.parity.as_deref() {
+        Some(s) => Some(super::repair::parse_parity(s)?),
+        None => defaults.parity,
+    };
+
+    check_tag_param(
+        config,
+        TagParam {
+            fragment_size,
+            zstd_level,
+            parity,
+            chunked: tc.chunked.unwrap_or(defaults.chunked),
+            last: tc.crypt_last.or(defaults.last),
+        },
+    )
+}
+
+fn check_tag_param(config: &Config, tag_param: TagParam) -> Result<TagParam> {
+    ensure!(
+        tag_param.last != Some(0),
+        "The number of versions must be >= 1"
+    );
+    if tag_param.chunked {
+        ensure!(
+            tag_param.parity.is_none(),
+            "Parity is not supported with chunked layout"
+        );
+        ensure!(
+            config.crypt.cipher().is_some() && config.crypt.passphrase_key().is_some(),
+            "Chunked layout requires a passphrase key: {}",
+            config.crypt
+        );
+    }
+
+    Ok(tag_param)
+}
+
+fn parse_fragment_size(s: &str) -> Result<NonZeroU64> {
     const FRAGMENT_MIN: u64 = 1024 * 1024;
+
+    let fragment = util::parse_size(s)?;
+    if fragment < FRAGMENT_MIN {
+        bail!("fragment size must be >= {FRAGMENT_MIN}");
+    }
+
+    Ok(NonZeroU64::new(fragment).unwrap())
+}
+
+/// 0 (no compression) or a valid zstd level
+fn check_zstd_level(level: i32) -> Result<i32> {
+    let range = zstd::compression_level_range();
+    ensure!(
+        level == 0 || range.contains(&level),
+        "zstd level must be 0 or in {}..={}",
+        range.start(),
+        range.end()
+    );
+
+    Ok(level)
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
     const FRAGMENT_DEFAULT: &str = "64m";
 
     const DESC: &str = "Split and encrypt the latest files in the repository.";
@@ -262,7 +831,54 @@ pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help");
-    opts.optopt("f", "flagment-size", "Split fragment size", "<SIZE>");
+    opts.optopt(
+        "f",
+        "flagment-size",
+        "Split fragment size (default=64m, overridden by tag config)",
+        "<SIZE>",
+    );
+    opts.optopt(
+        "z",
+        "zstd",
+        "Compress before encryption (default=0: no compression, overridden by tag config)",
+        "<LEVEL>",
+    );
+    opts.optopt(
+        "p",
+        "parity",
+        "Add K parity fragments per N fragments (e.g. 10+2, overridden by tag config)",
+        "<N+K>",
+    );
+    opts.optopt(
+        "l",
+        "last",
+        "Encrypt the newest N versions only (default: all, overridden by tag config)",
+        "N",
+    );
+    opts.optflag(
+        "r",
+        "recrypt-missing",
+        "Encrypt again the versions whose crypt data is missing (e.g. old versions \
+         overwritten in the old crypt/ layout)",
+    );
+    opts.optflag(
+        "c",
+        "chunked",
+        "Deduplicate by content-defined chunks in crypt/chunks/ (overridden by tag config)",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "Files processed at the same time (default: the number of CPUs)",
+        "N",
+    );
+    opts.optopt(
+        "M",
+        "memory",
+        "Buffer memory budget for all files (default: half of available memory)",
+        "<SIZE>",
+    );
+    PassphraseSource::add_options(&mut opts);
 
     if util::find_option(&args, &["-h", "--help"]) {
         println!("{}", crate::util::create_help(cmd, DESC, &opts, None));
@@ -271,15 +887,532 @@ pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
     let matches = opts.parse(args).context(USAGE_HINT)?;
 
     let fragment = matches.opt_str("f").unwrap_or(FRAGMENT_DEFAULT.to_string());
-    let fragment = util::parse_size(&fragment)?;
-    if fragment < FRAGMENT_MIN {
-        bail!("fragment size must be < {FRAGMENT_MIN}");
-    }
-    let fragment = NonZeroU64::new(fragment).unwrap();
+    let fragment = parse_fragment_size(&fragment)?;
+    let zstd_level = match matches.opt_str("z") {
+        Some(level) => check_zstd_level(
+            level
+                .parse()
+                .with_context(|| format!("Invalid level: {level}"))?,
+        )?,
+        None => 0,
+    };
+    let parity = matches
+        .opt_str("p")
+        .map(|s| super::repair::parse_parity(&s))
+        .transpose()?;
+    let defaults = TagParam {
+        fragment_size: fragment,
+        zstd_level,
+        parity,
+        chunked: matches.opt_present("c"),
+        last: matches.opt_get("l")?,
+    };
+    let recrypt_missing = matches.opt_present("r");
+    let source = PassphraseSource::from_matches(&matches)?;
+    let jobs = matches
+        .opt_get("j")?
+        .unwrap_or_else(util::TaskLimit::default_jobs);
+    let memory = match matches.opt_str("M") {
+        Some(s) => util::parse_size(&s).with_context(|| format!("Invalid size: {s}"))?,
+        None => util::TaskLimit::default_memory(),
+    };
+    info!(
+        "Jobs: {jobs}, Memory: {}",
+        util::size_to_human_readable(memory)
+    );
 
     super::process_with_config_lock_force_save(basedir, |basedir, config| {
-        process_crypt(basedir, config, fragment)
+        let limit = util::TaskLimit::new(jobs, memory);
+        process_crypt(
+            basedir,
+            config,
+            defaults,
+            source.clone(),
+            limit,
+            recrypt_missing,
+        )
     })?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use strum::{EnumMessage, IntoEnumIterator};
+
+    use super::super::{restore, Aes128GcmArgon2Param, RecipientKeys, WrappedKey};
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_crypt_round_trip() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        let password = "password";
+        let (salt, m_cost, t_cost, p_cost, kek) = cryptutil::aeskey_new_from_password(password);
+        let pk = PassphraseKey {
+            key_check: Some(cryptutil::aeskey_check(&kek)),
+            argon2: Aes128GcmArgon2Param {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            wrapped_key: None,
+        };
+        let data_key: AesKey = cryptutil::generate_random();
+        let (secret, public) = cryptutil::x25519_keypair_new();
+        let identity_path = dirpath.join("identity");
+        super::super::write_identity(&identity_path, &secret)?;
+
+        // 2.5 fragments
+        let plain: Vec<u8> = (0..2560u32).map(|x| (x * 7) as u8).collect();
+        let src_path = dirpath.join("a_20240101.bin");
+        std::fs::write(&src_path, &plain)?;
+        let fragment_size = NonZeroU64::new(1024).unwrap();
+
+        let rt = Runtime::new()?;
+        let compressions = [None, Some(Compression::Zstd { level: 3 })];
+        for (ctype, compression) in
+            CryptType::iter().flat_map(|t| compressions.map(|c| (t.clone(), c)))
+        {
+            let ctype = match ctype {
+                CryptType::X25519(_) => CryptType::X25519(RecipientKeys {
+                    recipients: vec![public],
+                    stanzas: Vec::new(),
+                }),
+                ctype => ctype.with_key(PassphraseKey {
+                    wrapped_key: Some(WrappedKey::wrap(&kek, &data_key)?),
+                    ..pk.clone()
+                }),
+            };
+            let name = format!(
+                "{}-{}",
+                ctype.get_serializations()[0],
+                compression.is_some()
+            );
+            let dst_dir_path = dirpath.join(&name);
+            std::fs::create_dir(&dst_dir_path)?;
+            let rf = RepositoryFile {
+                name: "a_20240101.bin".to_string(),
+                md5name: "a_20240101.bin.md5sum".to_string(),
+                crypt: false,
+            };
+            let param = TaskParam {
+                ctype: ctype.clone(),
+                key: ctype.cipher().map(|_| data_key),
+                repo_path: dirpath.to_path_buf(),
+                crypt_path: dirpath.to_path_buf(),
+                limit: util::TaskLimit::new(1, 0),
+            };
+            let fragment = FragmentParam {
+                size: fragment_size,
+                compression,
+            };
+            let info = rt.block_on(async {
+                match ctype {
+                    CryptType::PlainText => {
+                        process_file_plain(&src_path, &dst_dir_path, rf.clone(), fragment).await
+                    }
+                    _ => {
+                        process_file_aead(
+                            &src_path,
+                            &dst_dir_path,
+                            "a",
+                            rf.clone(),
+                            fragment,
+                            &param,
+                        )
+                        .await
+                    }
+                }
+            })?;
+            std::fs::write(
+                dst_dir_path.join(super::super::CRYPT_INFO_NAME),
+                toml::to_string(&info)?,
+            )?;
+
+            let info = restore::read_crypt_info(&dst_dir_path)?;
+            assert_eq!(info.crypt.cipher(), ctype.cipher());
+            assert_eq!(info.compression, compression);
+            assert!(restore::check_fragments(&dst_dir_path, &rf.name, &info).is_empty());
+            let buf = std::fs::read(dst_dir_path.join(super::super::fragment_name(&rf.name, 0)))?;
+            if let Some(cipher) = ctype.cipher() {
+                assert_eq!(cryptutil::FragmentHeader::parse(&buf)?.cipher, cipher);
+            }
+            if compression.is_some() {
+                assert!(buf.len() < 1024, "{name}");
+            } else if ctype.cipher().is_some() {
+                assert_ne!(&buf[buf.len() - 1024..], &plain[..1024]);
+            }
+
+            let mut keys = restore::KeyCache::with_passphrase(password.to_string());
+            keys.set_identity(Some(identity_path.clone()));
+            let dst_path = dirpath.join(format!("{name}.out"));
+            let md5 = rt.block_on(restore::decrypt_fragments(
+                &dst_dir_path,
+                "a",
+                &rf.name,
+                &info,
+                &mut keys,
+                Some(&dst_path),
+            ))?;
+            assert_eq!(std::fs::read(&dst_path)?, plain, "{name}");
+            assert_eq!(md5, *Md5::digest(&plain));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crypt_chunked() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let crypt_path = dirpath.join(super::super::DIRNAME_CRYPT);
+        let chunks_path = crypt_path.join(super::super::DIRNAME_CHUNKS);
+
+        let password = "password";
+        let (salt, m_cost, t_cost, p_cost, kek) = cryptutil::aeskey_new_from_password(password);
+        let data_key: AesKey = cryptutil::generate_random();
+        let ctype = CryptType::XChaCha20Poly1305Argon2(PassphraseKey {
+            key_check: Some(cryptutil::aeskey_check(&kek)),
+            argon2: Aes128GcmArgon2Param {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            wrapped_key: Some(WrappedKey::wrap(&kek, &data_key)?),
+        });
+        let param = TaskParam {
+            ctype,
+            key: Some(data_key),
+            repo_path: dirpath.to_path_buf(),
+            crypt_path: crypt_path.clone(),
+            limit: util::TaskLimit::new(1, 0),
+        };
+
+        // b: a few bytes changed in the middle of a
+        let mut x = 1u64;
+        let a: Vec<u8> = (0..(6 << 20))
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let mut b = a.clone();
+        b[3 << 20..(3 << 20) + 100].fill(0);
+
+        let rt = Runtime::new()?;
+        let count_chunks = || -> Result<usize> {
+            let mut count = 0;
+            for entry in chunks_path.read_dir()? {
+                count += entry?.path().read_dir()?.count();
+            }
+            Ok(count)
+        };
+        let mut keys = restore::KeyCache::with_passphrase(password.to_string());
+        for (tag, plain) in [("a", &a), ("b", &b)] {
+            let rf = RepositoryFile {
+                name: format!("{tag}_20240101.bin"),
+                md5name: format!("{tag}_20240101.bin.md5sum"),
+                crypt: false,
+            };
+            let src_path = dirpath.join(&rf.name);
+            std::fs::write(&src_path, plain)?;
+            let dst_dir_path = crypt_path.join(tag);
+            std::fs::create_dir_all(&dst_dir_path)?;
+            let zstd = Some(Compression::Zstd { level: 3 });
+            let info = rt.block_on(process_file_chunked(
+                &src_path,
+                &dst_dir_path,
+                tag,
+                rf.clone(),
+                zstd,
+                &param,
+            ))?;
+            std::fs::write(
+                dst_dir_path.join(super::super::CRYPT_INFO_NAME),
+                toml::to_string(&info)?,
+            )?;
+            assert!(restore::check_fragments(&dst_dir_path, &rf.name, &info).is_empty());
+
+            let md5 = rt.block_on(restore::decrypt_fragments(
+                &dst_dir_path,
+                tag,
+                &rf.name,
+                &info,
+                &mut keys,
+                None,
+            ))?;
+            assert_eq!(md5, *Md5::digest(plain));
+        }
+        // most chunks are shared
+        let total = count_chunks()?;
+        let refs = |tag| -> Result<Vec<String>> {
+            Ok(restore::read_crypt_info(&crypt_path.join(tag))?
+                .chunks
+                .unwrap()
+                .refs)
+        };
+        let b_refs = refs("b")?;
+        assert_eq!(total, refs("a")?.len().max(b_refs.len()) + 1);
+        let none = BTreeSet::new();
+        assert_eq!(chunk::remove_unreferenced(&crypt_path, &none, false)?.0, 0);
+
+        // dry run before removing a (prune --dry-run)
+        let removing = BTreeSet::from([crypt_path.join("a")]);
+        assert_eq!(
+            chunk::remove_unreferenced(&crypt_path, &removing, true)?.0,
+            1
+        );
+        assert_eq!(count_chunks()?, total);
+
+        // only chunks of a are removed
+        std::fs::remove_dir_all(crypt_path.join("a"))?;
+        assert_eq!(chunk::remove_unreferenced(&crypt_path, &none, true)?.0, 1);
+        assert_eq!(count_chunks()?, total);
+        assert_eq!(chunk::remove_unreferenced(&crypt_path, &none, false)?.0, 1);
+        assert_eq!(count_chunks()?, b_refs.len());
+        let info = restore::read_crypt_info(&crypt_path.join("b"))?;
+        let md5 = rt.block_on(restore::decrypt_fragments(
+            &crypt_path.join("b"),
+            "b",
+            "b_20240101.bin",
+            &info,
+            &mut keys,
+            None,
+        ))?;
+        assert_eq!(md5, *Md5::digest(&b));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_choose_compression() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let rt = Runtime::new()?;
+        let zstd = Some(Compression::Zstd { level: 3 });
+
+        let text = dirpath.join("text");
+        std::fs::write(&text, "hello ".repeat(1000))?;
+        let random = dirpath.join("random");
+        std::fs::write(&random, cryptutil::generate_random::<4096>())?;
+
+        let choose = |path: &Path, name: &str, level: i32| {
+            rt.block_on(choose_compression(path, name, level))
+        };
+        assert_eq!(choose(&text, "a_20240101.tar", 3)?, zstd);
+        assert_eq!(choose(&text, "a_20240101.tar", 0)?, None);
+        assert_eq!(choose(&text, "a_20240101.tar.XZ", 3)?, None);
+        assert_eq!(choose(&text, "a_20240101.7z", 3)?, None);
+        assert_eq!(choose(&random, "a_20240101.tar", 3)?, None);
+
+        assert!(check_zstd_level(0).is_ok());
+        assert!(check_zstd_level(19).is_ok());
+        assert!(check_zstd_level(100).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crypt_last() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tagdir = dirpath.join(super::super::DIRNAME_REPO).join("a");
+        std::fs::create_dir_all(&tagdir)?;
+
+        let mut config = Config::default();
+        let mut ents = BTreeSet::new();
+        for date in ["20240101", "20240102", "20240103"] {
+            let rf = RepositoryFile {
+                name: format!("a_{date}.bin"),
+                md5name: format!("a_{date}.bin.md5sum"),
+                crypt: false,
+            };
+            std::fs::write(tagdir.join(&rf.name), date)?;
+            ents.insert(std::cmp::Reverse(rf));
+        }
+        config.repository.entries.insert("a".to_string(), ents);
+
+        let defaults = TagParam {
+            fragment_size: NonZeroU64::new(1024).unwrap(),
+            zstd_level: 0,
+            parity: None,
+            chunked: false,
+            last: Some(2),
+        };
+        let (config, res) = process_crypt(
+            dirpath,
+            config,
+            defaults,
+            Default::default(),
+            util::TaskLimit::new(1, 0),
+            false,
+        );
+        res?;
+        let config = config.unwrap();
+
+        // the newest 2 versions only, each in crypt/tag/version
+        let crypt_path = dirpath.join(super::super::DIRNAME_CRYPT);
+        let crypted: Vec<_> = config.repository.entries["a"]
+            .iter()
+            .map(|rf| rf.0.crypt)
+            .collect();
+        assert_eq!(crypted, [true, true, false]);
+        let versions = restore::crypt_versions(&crypt_path.join("a"))?;
+        assert_eq!(
+            versions.into_iter().collect::<Vec<_>>(),
+            ["a_20240102.bin", "a_20240103.bin"]
+        );
+
+        // crypt data lost: processed again if requested
+        std::fs::remove_dir_all(crypt_path.join("a").join("a_20240103.bin"))?;
+        let defaults = TagParam {
+            last: None,
+            ..defaults
+        };
+        let (config, res) = process_crypt(
+            dirpath,
+            config,
+            defaults,
+            Default::default(),
+            util::TaskLimit::new(1, 0),
+            true,
+        );
+        res?;
+        assert!(config.unwrap().repository.entries["a"]
+            .iter()
+            .all(|rf| rf.0.crypt));
+        assert_eq!(restore::crypt_versions(&crypt_path.join("a"))?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crypt_old_layout() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tagdir = dirpath.join(super::super::DIRNAME_REPO).join("a");
+        let crypt_path = dirpath.join(super::super::DIRNAME_CRYPT);
+        let crypt_tag_path = crypt_path.join("a");
+        std::fs::create_dir_all(&tagdir)?;
+
+        let defaults = TagParam {
+            fragment_size: NonZeroU64::new(1024).unwrap(),
+            zstd_level: 0,
+            parity: None,
+            chunked: false,
+            last: None,
+        };
+        let run = |config, recrypt_missing| -> Result<Config> {
+            let (config, res) = process_crypt(
+                dirpath,
+                config,
+                defaults,
+                Default::default(),
+                util::TaskLimit::new(1, 0),
+                recrypt_missing,
+            );
+            res?;
+            Ok(config.unwrap())
+        };
+        let add = |config: &mut Config, date: &str| -> Result<()> {
+            let rf = RepositoryFile {
+                name: format!("a_{date}.bin"),
+                md5name: format!("a_{date}.bin.md5sum"),
+                crypt: false,
+            };
+            std::fs::write(tagdir.join(&rf.name), date)?;
+            config
+                .repository
+                .entries
+                .entry("a".to_string())
+                .or_default()
+                .insert(std::cmp::Reverse(rf));
+            Ok(())
+        };
+
+        // old layout: crypt/a/ has the newest one only (0101 was overwritten)
+        let mut config = Config::default();
+        add(&mut config, "20240101")?;
+        add(&mut config, "20240102")?;
+        let mut config = run(config, false)?;
+        std::fs::remove_dir_all(crypt_tag_path.join("a_20240101.bin"))?;
+        let version_path = crypt_tag_path.join("a_20240102.bin");
+        for entry in version_path.read_dir()? {
+            let entry = entry?;
+            std::fs::rename(entry.path(), crypt_tag_path.join(entry.file_name()))?;
+        }
+        std::fs::remove_dir(&version_path)?;
+        assert_eq!(
+            restore::find_crypt_dir(&crypt_path, "a", "a_20240102.bin"),
+            Some(crypt_tag_path.clone())
+        );
+
+        // upgrade: the new version only
+        add(&mut config, "20240103")?;
+        let config = run(config, false)?;
+        assert!(config.repository.entries["a"].iter().all(|rf| rf.0.crypt));
+        assert_eq!(
+            restore::crypt_versions(&crypt_tag_path)?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["a_20240102.bin", "a_20240103.bin"]
+        );
+        assert!(restore::find_crypt_dir(&crypt_path, "a", "a_20240101.bin").is_none());
+
+        // --recrypt-missing: the overwritten one too
+        run(config, true)?;
+        assert_eq!(restore::crypt_versions(&crypt_tag_path)?.len(), 3);
+        assert_eq!(
+            restore::find_crypt_dir(&crypt_path, "a", "a_20240102.bin"),
+            Some(crypt_tag_path.clone())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clean_staging() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let crypt_path = tmpdir.path();
+        let staging_path = crypt_path.join(super::super::DIRNAME_STAGING);
+
+        // no staging dir
+        clean_staging(crypt_path)?;
+
+        // a: half-written, b: interrupted while replacing
+        // c: interrupted before deleting the old one
+        std::fs::create_dir_all(staging_path.join("a"))?;
+        std::fs::create_dir_all(staging_path.join("b.old"))?;
+        std::fs::write(staging_path.join("b.old").join("x"), "old")?;
+        std::fs::create_dir_all(staging_path.join("c.old"))?;
+        std::fs::create_dir_all(crypt_path.join("c"))?;
+        // the same in crypt/.staging/tag/
+        std::fs::create_dir_all(staging_path.join("t").join("a"))?;
+        std::fs::create_dir_all(staging_path.join("t").join("b.old"))?;
+        std::fs::write(staging_path.join("t").join("b.old").join("x"), "old")?;
+        clean_staging(crypt_path)?;
+
+        assert_eq!(staging_path.read_dir()?.count(), 0);
+        assert_eq!(
+            std::fs::read_to_string(crypt_path.join("b").join("x"))?,
+            "old"
+        );
+        assert!(crypt_path.join("c").is_dir());
+        assert_eq!(
+            std::fs::read_to_string(crypt_path.join("t").join("b").join("x"))?,
+            "old"
+        );
+        assert!(!crypt_path.join("t").join("a").exists());
+
+        Ok(())
+    }
+}
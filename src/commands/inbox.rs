@@ -1,17 +1,22 @@
 use std::cmp::Reverse;
 use std::collections::BTreeSet;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
 use getopts::Options;
-use md5::{Digest, Md5};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use log::warn;
+use tokio::io::AsyncWriteExt;
 use tokio::runtime::Runtime;
 
 use super::{Config, RepositoryFile};
-use crate::util;
+use crate::util::{self, TaskLimit};
+
+/// Written into repo/tag/ first, and renamed after MD5 verification
+const TMPEXT: &str = "tmp";
 
 #[derive(Default)]
 struct ProcessStat {
@@ -20,7 +25,12 @@ struct ProcessStat {
     error: AtomicU32,
 }
 
-async fn process_dir(stat: Arc<ProcessStat>, inbox_path: &Path, repo_path: &Path) {
+async fn process_dir(
+    stat: Arc<ProcessStat>,
+    inbox_path: &Path,
+    repo_path: &Path,
+    limit: Arc<TaskLimit>,
+) {
     println!("Process {}", inbox_path.display());
 
     // get directory iterator (sync)
@@ -49,7 +59,12 @@ async fn process_dir(stat: Arc<ProcessStat>, inbox_path: &Path, repo_path: &Path
         let repo_path = PathBuf::from(repo_path);
         if path.is_file() {
             // execute on a separated thread
-            let h = tokio::spawn(async move { process_file(&path, &repo_path).await });
+            let limit = Arc::clone(&limit);
+            let h = tokio::spawn(async move {
+                // small buffers only (memory is not limited)
+                let _permit = limit.acquire(0).await?;
+                process_file(&path, &repo_path).await
+            });
             handles.push(h);
         } else {
             println!("Not a regular file {}", path.display());
@@ -73,12 +88,114 @@ async fn process_dir(stat: Arc<ProcessStat>, inbox_path: &Path, repo_path: &Path
     }
 }
 
+/// How a file was moved into repo/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ingest {
+    /// The same filesystem (no data copy)
+    Move,
+    /// Copy-on-write clone (no data copy)
+    Reflink,
+    /// Streaming copy
+    Copy,
+}
+
+/// Move src to dst and compare MD5 with md5 by reading the data only once.
+///
+/// Try rename, reflink (another filesystem) and then copy (reflink is not supported).
+/// MD5 is of dst if moved or cloned, of the bytes written if copied.
+/// If failed, src is kept and dst is not created.
+/// Return the method and the file size.
+async fn ingest_file(src: &Path, dst: &Path, md5: &[u8; util::MD5LEN]) -> Result<(Ingest, u64)> {
+    let mut tmp = dst.as_os_str().to_os_string();
+    tmp.push(format!(".{TMPEXT}"));
+    let tmp = PathBuf::from(tmp);
+
+    // left by an interrupted run (reflink fails if exists)
+    let result = tokio::fs::remove_file(&tmp).await;
+    if let Err(ref err) = result {
+        if err.kind() != ErrorKind::NotFound {
+            result.with_context(|| format!("Delete failed: {}", tmp.display()))?;
+        }
+    }
+
+    let method = match tokio::fs::rename(src, &tmp).await {
+        Ok(()) => Ingest::Move,
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            match reflink_blocking(src, &tmp).await? {
+                Ok(()) => Ingest::Reflink,
+                Err(err) if reflink_unsupported(&err) => Ingest::Copy,
+                Err(err) => {
+                    let _ = tokio::fs::remove_file(&tmp).await;
+                    return Err(err).with_context(|| format!("Reflink failed: {}", src.display()));
+                }
+            }
+        }
+        Err(err) => return Err(err).with_context(|| format!("Rename failed: {}", src.display())),
+    };
+    let result = match method {
+        Ingest::Move | Ingest::Reflink => util::md5_file(&tmp).await,
+        Ingest::Copy => util::copy_md5(src, &tmp).await.map(|(md5, _)| md5),
+    };
+    if !matches!(&result, Ok(result) if result == md5) {
+        // keep src in inbox/
+        if method == Ingest::Move {
+            tokio::fs::rename(&tmp, src)
+                .await
+                .with_context(|| format!("Rename failed: {}", tmp.display()))?;
+        } else if let Err(err) = tokio::fs::remove_file(&tmp).await {
+            warn!("Delete failed: {}: {err}", tmp.display());
+        }
+        result?;
+        bail!("MD5 unmatch: {}", src.display());
+    }
+    println!("MD5 verify OK: {}", tmp.display());
+
+    let file = tokio::fs::File::open(&tmp).await?;
+    // already synced by copy
+    if method != Ingest::Copy {
+        file.sync_all().await?;
+    }
+    let size = file.metadata().await?.len();
+    drop(file);
+    tokio::fs::rename(&tmp, dst)
+        .await
+        .with_context(|| format!("Rename failed: {}", tmp.display()))?;
+    if let Some(dir) = dst.parent() {
+        util::sync_dir_async(dir).await?;
+    }
+    if method != Ingest::Move {
+        tokio::fs::remove_file(src).await?;
+        println!("Delete OK: {}", src.display());
+    }
+
+    Ok((method, size))
+}
+
+async fn reflink_blocking(src: &Path, dst: &Path) -> Result<std::io::Result<()>> {
+    let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+    util::blocking(move || Ok(reflink_copy::reflink(src, dst))).await
+}
+
+/// The filesystem (or the platform) does not support reflink between them.
+fn reflink_unsupported(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if matches!(
+        err.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::EXDEV)
+    ) {
+        return true;
+    }
+
+    matches!(
+        err.kind(),
+        ErrorKind::Unsupported | ErrorKind::CrossesDevices | ErrorKind::InvalidInput
+    )
+}
+
 async fn process_file(
     file_path: &Path,
     repo_path: &Path,
 ) -> Result<Option<(String, RepositoryFile)>> {
-    const BUFSIZE: usize = 64 * 1024;
-
     // only UTF-8 path is valid
     file_path
         .to_str()
@@ -107,32 +224,15 @@ async fn process_file(
     let md5 = util::str_to_md5(&md5str)
         .with_context(|| format!("Failed to convert to MD5 {}", md5path.display()))?;
 
-    // read the file and calc md5
-    let mut fin = tokio::fs::File::open(&file_path).await?;
-    let mut buf = vec![0u8; BUFSIZE];
-    let mut hasher = Md5::new();
-    loop {
-        let read_size = fin.read(&mut buf).await?;
-        if read_size == 0 {
-            break;
-        }
-        hasher.update(&buf[..read_size]);
-    }
-    let result = hasher.finalize();
-
-    // verify md5
-    ensure!(*result == md5, "MD5 unmatch");
-    println!("MD5 verify OK: {}", file_path.display());
-
-    // copy
+    // move (or copy) and verify md5 in one pass
     let destdir = repo_path.join(tag);
     tokio::fs::create_dir_all(&destdir).await?;
     let dest_file_name = format!("{tag}_{date}.{ext}");
     {
         let destfile = destdir.join(&dest_file_name);
-        let size = tokio::fs::copy(&file_path, &destfile).await?;
+        let (method, size) = ingest_file(file_path, &destfile, &md5).await?;
         println!(
-            "Copy OK: {} => {} ({})",
+            "{method:?} OK: {} => {} ({})",
             file_path.display(),
             destfile.display(),
             util::size_to_human_readable(size)
@@ -146,8 +246,6 @@ async fn process_file(
         println!("Write OK: {}", destfile.display());
     }
 
-    tokio::fs::remove_file(&file_path).await?;
-    println!("Delete OK: {}", file_path.display());
     tokio::fs::remove_file(&md5path).await?;
     println!("Delete OK: {}", md5path.display());
 
@@ -161,13 +259,49 @@ async fn process_file(
     )))
 }
 
-fn process_inbox(dirpath: &Path, mut config: Config) -> Result<Option<Config>> {
+/// Warn if the latest file of a tag is older than the expected interval in tag config.
+fn check_interval(config: &Config) {
+    let now = Local::now().naive_local();
+    for (tag, tc) in config.tags.iter() {
+        let Some(interval_str) = &tc.interval else {
+            continue;
+        };
+        let interval = match util::parse_interval(interval_str) {
+            Ok(interval) => interval,
+            Err(err) => {
+                warn!("[{tag}] Invalid interval: {interval_str}: {:#}", err);
+                continue;
+            }
+        };
+
+        let latest = config
+            .repository
+            .entries
+            .get(tag)
+            .and_then(|ents| ents.first())
+            .and_then(|rf| super::split_filename(&rf.0.name).ok())
+            .and_then(|(_, date, _)| super::parse_date(date).ok());
+        match latest {
+            Some(date) if now - date <= interval => {}
+            Some(date) => warn!("[{tag}] No new file since {date} (interval {interval_str})"),
+            None => warn!("[{tag}] No file (interval {interval_str})"),
+        }
+    }
+}
+
+fn process_inbox(dirpath: &Path, mut config: Config, jobs: usize) -> Result<Option<Config>> {
     let inbox_path = dirpath.join(super::DIRNAME_INBOX);
     let repo_path = dirpath.join(super::DIRNAME_REPO);
 
     let stat: Arc<ProcessStat> = Arc::new(Default::default());
     let rt = Runtime::new()?;
-    rt.block_on(process_dir(Arc::clone(&stat), &inbox_path, &repo_path));
+    let limit = Arc::new(TaskLimit::new(jobs, u64::MAX));
+    rt.block_on(process_dir(
+        Arc::clone(&stat),
+        &inbox_path,
+        &repo_path,
+        limit,
+    ));
     drop(rt);
 
     let processed = stat.processed.lock().unwrap();
@@ -190,6 +324,8 @@ fn process_inbox(dirpath: &Path, mut config: Config) -> Result<Option<Config>> {
             }
         }
     }
+    check_interval(&config);
+
     config.system.update();
     Ok(Some(config))
 }
@@ -201,12 +337,65 @@ pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help");
+    opts.optopt(
+        "j",
+        "jobs",
+        "Files processed at the same time (default: the number of CPUs)",
+        "N",
+    );
 
     if util::find_option(&args, &["-h", "--help"]) {
         println!("{}", crate::util::create_help(cmd, DESC, &opts, None));
         return Ok(());
     }
-    let _matches = opts.parse(args).context(USAGE_HINT)?;
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let jobs = matches
+        .opt_get("j")?
+        .unwrap_or_else(TaskLimit::default_jobs);
+
+    super::process_with_config_lock(basedir, |basedir, config| {
+        process_inbox(basedir, config, jobs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+    use tempdir::TempDir;
 
-    super::process_with_config_lock(basedir, process_inbox)
+    use super::*;
+
+    #[test]
+    fn test_ingest_file() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let src = tmpdir.path().join("a_20240101.bin");
+        let dst = tmpdir.path().join("repo_a_20240101.bin");
+        std::fs::write(&src, "hello")?;
+        let md5: [u8; util::MD5LEN] = Md5::digest("hello").into();
+        let rt = Runtime::new()?;
+
+        // MD5 unmatch: src is kept
+        let wrong: [u8; util::MD5LEN] = Md5::digest("hellO").into();
+        assert!(rt.block_on(ingest_file(&src, &dst, &wrong)).is_err());
+        assert_eq!(std::fs::read_to_string(&src)?, "hello");
+        assert!(!dst.exists());
+
+        // the same filesystem
+        let (method, size) = rt.block_on(ingest_file(&src, &dst, &md5))?;
+        assert_eq!(method, Ingest::Move);
+        assert_eq!(size, 5);
+        assert!(!src.exists());
+        assert_eq!(std::fs::read_to_string(&dst)?, "hello");
+        assert_eq!(tmpdir.path().read_dir()?.count(), 1);
+
+        // not copied if failed for another reason than another filesystem
+        let err = rt.block_on(ingest_file(&src, &dst, &md5)).unwrap_err();
+        assert!(format!("{err:#}").contains("Rename failed"), "{err:#}");
+        assert_eq!(std::fs::read_to_string(&dst)?, "hello");
+        assert!(reflink_unsupported(&ErrorKind::Unsupported.into()));
+        assert!(!reflink_unsupported(&ErrorKind::PermissionDenied.into()));
+        assert!(!reflink_unsupported(&ErrorKind::StorageFull.into()));
+
+        Ok(())
+    }
 }
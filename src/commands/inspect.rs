@@ -0,0 +1,156 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use getopts::Options;
+use log::{info, warn};
+
+use crate::cryptutil::FragmentHeader;
+use crate::util;
+
+/// Return (header, file size, payload size).
+fn read_header(path: &Path) -> Result<(FragmentHeader, u64, u64)> {
+    let file_size = path
+        .metadata()
+        .with_context(|| format!("Cannot read {}", path.display()))?
+        .len();
+
+    // header only
+    let mut buf = Vec::new();
+    std::fs::File::open(path)
+        .with_context(|| format!("Cannot open {}", path.display()))?
+        .take(u16::MAX as u64)
+        .read_to_end(&mut buf)?;
+    let header = FragmentHeader::parse(&buf)
+        .with_context(|| format!("Invalid header: {}", path.display()))?;
+    let payload_size = file_size.saturating_sub(header.header_len() as u64);
+    let tag_size = header.cipher.tag_size() as u64;
+    ensure!(payload_size >= tag_size, "Too short: {}", path.display());
+
+    Ok((header, file_size, payload_size))
+}
+
+fn process_inspect(path: &Path) -> Result<()> {
+    let (header, file_size, payload_size) = read_header(path)?;
+    let tag_size = header.cipher.tag_size() as u64;
+
+    let to_hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(String::new(), |cur, b| cur + &format!("{:02x}", b))
+    };
+    let salt_str = to_hex(&header.salt);
+    let nonce_str = to_hex(&header.nonce);
+
+    info!("File    : {} ({} bytes)", path.display(), file_size);
+    if header.version == 0 {
+        warn!("Magic not found, assume the old format (or not encrypted)");
+    }
+    info!("Version : {}", header.version);
+    info!("Header  : {} bytes", header.header_len());
+    info!("Cipher  : {:?}", header.cipher);
+    info!("KDF     : {:?}", header.kdf);
+    info!("salt    : {salt_str}");
+    info!("m_cost  : {}", header.m_cost);
+    info!("t_cost  : {}", header.t_cost);
+    info!("p_cost  : {}", header.p_cost);
+    match header.index {
+        Some(index) => info!("Index   : {index}"),
+        None => info!("Index   : NODATA"),
+    }
+    info!("Nonce   : {nonce_str}");
+    info!(
+        "Payload : {} bytes (plain {} bytes + tag {} bytes)",
+        payload_size,
+        payload_size - tag_size,
+        tag_size
+    );
+
+    Ok(())
+}
+
+pub fn entry(_basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str =
+        "Print the header of an encrypted fragment file (config file is not needed).";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, Some("FRAGMENT")));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+
+    ensure!(matches.free.len() == 1, "FRAGMENT is required");
+    let path = Path::new(&matches.free[0]);
+
+    process_inspect(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptutil::{self, CipherId, KdfId};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_read_header() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let path = tmpdir.path().join("a_20240101.bin.000001");
+
+        // version 1
+        let nonce: Vec<u8> = (0..24).collect();
+        let header = FragmentHeader::new(
+            CipherId::XChaCha20Poly1305,
+            [7u8; 16],
+            1024,
+            2,
+            3,
+            1,
+            nonce.clone(),
+        );
+        let mut buf = header.to_bytes();
+        buf.extend([0u8; 100]);
+        std::fs::write(&path, &buf)?;
+        let (parsed, file_size, payload_size) = read_header(&path)?;
+        assert_eq!(parsed, header);
+        assert_eq!(parsed.version, cryptutil::FRAGMENT_VERSION);
+        assert_eq!(parsed.kdf, KdfId::Argon2id);
+        assert_eq!(parsed.index, Some(1));
+        assert_eq!(parsed.nonce, nonce);
+        assert_eq!(file_size, buf.len() as u64);
+        assert_eq!(payload_size, 100);
+        process_inspect(&path)?;
+
+        // version 0: salt, m, t, p, nonce (AES-GCM)
+        let mut buf = vec![9u8; 16];
+        for cost in [4096u32, 4, 5] {
+            buf.extend(cost.to_le_bytes());
+        }
+        buf.extend(0..12u8);
+        buf.extend([0u8; 16]);
+        std::fs::write(&path, &buf)?;
+        let (parsed, _, payload_size) = read_header(&path)?;
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.cipher, CipherId::Aes256Gcm);
+        assert_eq!(parsed.salt, [9u8; 16]);
+        assert_eq!((parsed.m_cost, parsed.t_cost, parsed.p_cost), (4096, 4, 5));
+        assert_eq!(parsed.index, None);
+        assert_eq!(parsed.nonce, (0..12u8).collect::<Vec<_>>());
+        assert_eq!(payload_size, 16);
+
+        // shorter than the tag
+        buf.truncate(buf.len() - 1);
+        std::fs::write(&path, &buf)?;
+        let err = read_header(&path).unwrap_err();
+        assert!(format!("{err:#}").contains("Too short"));
+        // header only (truncated)
+        std::fs::write(&path, &buf[..10])?;
+        assert!(read_header(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -1,64 +1,379 @@
-use std::{path::Path, str::FromStr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::{ensure, Context, Result};
-use dialoguer::Password;
+use anyhow::{bail, ensure, Context, Result};
 use getopts::Options;
-use log::info;
+use log::{info, warn};
 use strum::{EnumMessage, IntoEnumIterator};
 
+use super::restore::{self, KeyCache};
 use crate::{
-    commands::{Aes128GcmArgon2Param, Config, CryptType, CONFIG_FILE_NAME},
-    cryptutil, util,
+    commands::{
+        Aes128GcmArgon2Param, Config, CryptInfo, CryptType, PassphraseKey, PassphraseSource,
+        RecipientKeys, Secrets, WrappedKey, SECRETS_FILE_NAME,
+    },
+    cryptutil::{self, AesKey},
+    util,
 };
 
-fn genkey_plaintext(mut config: Config) -> Result<Option<Config>> {
+const ACTION_CHANGE_PASSPHRASE: &str = "change-passphrase";
+const ACTION_GEN_KEYPAIR: &str = "gen-keypair";
+/// metadata.toml.rewrap: replaces metadata.toml after config.toml is saved
+const REWRAP_SUFFIX: &str = ".rewrap";
+
+fn genkey_plaintext(dirpath: &Path, mut config: Config) -> Result<Option<Config>> {
     config.crypt = CryptType::PlainText;
+    super::write_secrets(dirpath, None)?;
 
     Ok(Some(config))
 }
 
-fn genkey_aes(mut config: Config) -> Result<Option<Config>> {
+/// ctype: a passphrase-based type (the key in it is replaced)
+fn genkey_passphrase(
+    dirpath: &Path,
+    mut config: Config,
+    ctype: CryptType,
+    save: bool,
+    source: &PassphraseSource,
+) -> Result<Option<Config>> {
     info!("Generate a new encrypt/decrypt key");
 
-    let password = Password::new()
-        .with_prompt("Passphrase")
-        .allow_empty_password(true)
-        .with_confirmation("Input again", "Passphrase mismatch")
-        .interact()?;
+    let password = source.read("Passphrase", true)?;
 
     let (salt, m_cost, t_cost, p_cost, key) = cryptutil::aeskey_new_from_password(&password);
-    config.crypt = CryptType::Aes128GcmArgon2 {
-        key: Some(key),
-        argon2: Aes128GcmArgon2Param {
-            salt,
-            m_cost,
-            t_cost,
-            p_cost,
-        },
+    let argon2 = Aes128GcmArgon2Param {
+        salt,
+        m_cost,
+        t_cost,
+        p_cost,
     };
+    // random data key wrapped by the derived key
+    let data_key: AesKey = cryptutil::generate_random();
+    config.crypt = ctype.with_key(PassphraseKey {
+        key_check: Some(cryptutil::aeskey_check(&key)),
+        argon2: argon2.clone(),
+        wrapped_key: Some(WrappedKey::wrap(&key, &data_key)?),
+    });
 
     info!("New salt and key created: {}", config.crypt);
-    info!(
-        "New AES en/decrypt key created: (Saved into {})",
-        CONFIG_FILE_NAME
+    if save {
+        super::write_secrets(dirpath, Some(&Secrets { argon2, key }))?;
+        info!(
+            "New en/decrypt key created: (Saved into {})",
+            SECRETS_FILE_NAME
+        );
+    } else {
+        super::write_secrets(dirpath, None)?;
+        info!("New en/decrypt key created: (Not saved, passphrase needed)");
+    }
+
+    Ok(Some(config))
+}
+
+/// Only public keys are saved (no secret on this machine).
+fn genkey_x25519(
+    dirpath: &Path,
+    mut config: Config,
+    recipients: &[String],
+) -> Result<Option<Config>> {
+    ensure!(
+        !recipients.is_empty(),
+        "--recipient is required (see {ACTION_GEN_KEYPAIR})"
     );
+    let recipients = recipients
+        .iter()
+        .map(|s| super::x25519_key_from_str(s))
+        .collect::<Result<_>>()?;
+
+    config.crypt = CryptType::X25519(RecipientKeys {
+        recipients,
+        stanzas: Vec::new(),
+    });
+    super::write_secrets(dirpath, None)?;
+    info!("Public keys saved: {}", config.crypt);
 
     Ok(Some(config))
 }
 
-fn process_key(config: Config, ctype: Option<&str>) -> Result<Option<Config>> {
+/// Write a new private key to path and print the public key.
+fn gen_keypair(path: &Path) -> Result<()> {
+    let (secret, public) = cryptutil::x25519_keypair_new();
+    super::write_identity(path, &secret)?;
+    info!("Private key saved: {} (keep it offline)", path.display());
+    info!("Public key: {}", super::x25519_key_to_str(&public));
+
+    Ok(())
+}
+
+fn rewrap_path(info_path: &Path) -> PathBuf {
+    let mut path = info_path.as_os_str().to_os_string();
+    path.push(REWRAP_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Re-wrap data keys in crypt/\*/\*/metadata.toml encrypted with old_argon2.
+///
+/// Old format data (not wrapped) is also converted;
+/// the old derived key becomes its data key.
+/// The new ones are staged as metadata.toml.rewrap (see [commit_rewrap]).
+/// Return the paths of metadata.toml to be replaced.
+fn rewrap_crypt_dirs(
+    dirpath: &Path,
+    old_argon2: &Aes128GcmArgon2Param,
+    old_kek: &AesKey,
+    new_argon2: &Aes128GcmArgon2Param,
+    new_kek: &AesKey,
+) -> Result<Vec<PathBuf>> {
+    let mut staged = Vec::new();
+    let result = stage_rewrap(
+        dirpath,
+        old_argon2,
+        old_kek,
+        new_argon2,
+        new_kek,
+        &mut staged,
+    );
+    if let Err(err) = result {
+        discard_rewrap(&staged);
+        return Err(err);
+    }
+
+    Ok(staged)
+}
+
+fn stage_rewrap(
+    dirpath: &Path,
+    old_argon2: &Aes128GcmArgon2Param,
+    old_kek: &AesKey,
+    new_argon2: &Aes128GcmArgon2Param,
+    new_kek: &AesKey,
+    staged: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
+    for path in restore::crypt_dirs(&crypt_path)? {
+        let mut info = match restore::read_crypt_info(&path) {
+            Ok(info) => info,
+            Err(err) => {
+                warn!("Skip: {:#}", err);
+                continue;
+            }
+        };
+        let Some(PassphraseKey {
+            argon2,
+            wrapped_key,
+            ..
+        }) = info.crypt.passphrase_key_mut()
+        else {
+            continue;
+        };
+        if argon2 != old_argon2 {
+            warn!(
+                "Skip (encrypted with another passphrase): {}",
+                path.display()
+            );
+            continue;
+        }
+
+        let data_key = match wrapped_key {
+            Some(wrapped) => wrapped
+                .unwrap(old_kek)
+                .with_context(|| format!("Invalid key: {}", path.display()))?,
+            None => *old_kek,
+        };
+        *argon2 = new_argon2.clone();
+        *wrapped_key = Some(WrappedKey::wrap(new_kek, &data_key)?);
+
+        let info_path = path.join(super::CRYPT_INFO_NAME);
+        let new_path = rewrap_path(&info_path);
+        util::write_atomic(&new_path, toml::to_string(&info)?)
+            .with_context(|| format!("Cannot write {}", new_path.display()))?;
+        staged.push(info_path);
+    }
+
+    Ok(())
+}
+
+/// Replace metadata.toml with metadata.toml.rewrap.
+fn commit_rewrap(staged: &[PathBuf]) -> Result<()> {
+    let mut failed = 0;
+    for info_path in staged {
+        let new_path = rewrap_path(info_path);
+        match std::fs::rename(&new_path, info_path) {
+            Ok(()) => info!("Rewrap: {}", info_path.display()),
+            Err(err) => {
+                warn!("Rename failed: {}: {err}", new_path.display());
+                failed += 1;
+            }
+        }
+    }
+    ensure!(
+        failed == 0,
+        "{failed} metadata file(s) not replaced (*{REWRAP_SUFFIX} is committed by the next run)"
+    );
+
+    Ok(())
+}
+
+/// Delete metadata.toml.rewrap (metadata.toml is kept as is).
+fn discard_rewrap(staged: &[PathBuf]) {
+    for info_path in staged {
+        let new_path = rewrap_path(info_path);
+        if let Err(err) = std::fs::remove_file(&new_path) {
+            warn!("Delete failed: {}: {err}", new_path.display());
+        }
+    }
+}
+
+/// Commit or delete metadata.toml.rewrap left by an interrupted change-passphrase.
+///
+/// Committed if config.toml had been saved (the same Argon2 parameters as config),
+/// deleted otherwise. The lock must be held.
+pub(super) fn clean_rewrap(dirpath: &Path, config: &Config) -> Result<()> {
+    let argon2 = config.crypt.passphrase_key().map(|pk| &pk.argon2);
+    for path in restore::crypt_dirs(&dirpath.join(super::DIRNAME_CRYPT))? {
+        let info_path = path.join(super::CRYPT_INFO_NAME);
+        let new_path = rewrap_path(&info_path);
+        if !new_path.is_file() {
+            continue;
+        }
+
+        let staged = std::fs::read_to_string(&new_path)
+            .ok()
+            .and_then(|toml| toml::from_str::<CryptInfo>(&toml).ok());
+        let staged_argon2 = staged
+            .as_ref()
+            .and_then(|info| info.crypt.passphrase_key())
+            .map(|pk| &pk.argon2);
+        if staged_argon2.is_some() && staged_argon2 == argon2 {
+            warn!(
+                "Interrupted change-passphrase detected, commit: {}",
+                new_path.display()
+            );
+            std::fs::rename(&new_path, &info_path)
+                .with_context(|| format!("Rename failed: {}", new_path.display()))?;
+            util::sync_dir(&path)?;
+        } else {
+            warn!(
+                "Interrupted change-passphrase detected, delete: {}",
+                new_path.display()
+            );
+            std::fs::remove_file(&new_path)
+                .with_context(|| format!("Delete failed: {}", new_path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Wrap the data key with a new passphrase.
+///
+/// Existing data in crypt/ is kept as is (no re-encryption needed).
+/// Return the new config, staged metadata (see [rewrap_crypt_dirs])
+/// and the new secrets to be saved.
+fn change_passphrase(
+    dirpath: &Path,
+    mut config: Config,
+    old_source: &PassphraseSource,
+    source: &PassphraseSource,
+) -> Result<(Config, Vec<PathBuf>, Option<Secrets>)> {
+    let Some(PassphraseKey {
+        argon2: old_argon2,
+        wrapped_key: old_wrapped,
+        ..
+    }) = config.crypt.passphrase_key()
+    else {
+        bail!("Passphrase is not used: {}", config.crypt);
+    };
+
+    // the current one is from the saved key or old_source,
+    // the new one is from the source
+    let mut keys = KeyCache::load(dirpath, &config, old_source.clone())?;
+    let old_kek = keys.get(old_argon2)?;
+    let data_key = keys.data_key(old_argon2, old_wrapped.as_ref())?;
+
+    let password = source.read("New passphrase", true)?;
+    let (salt, m_cost, t_cost, p_cost, kek) = cryptutil::aeskey_new_from_password(&password);
+    let argon2 = Aes128GcmArgon2Param {
+        salt,
+        m_cost,
+        t_cost,
+        p_cost,
+    };
+
+    let saved = super::read_secrets(dirpath)?.is_some();
+    let new_pk = PassphraseKey {
+        key_check: Some(cryptutil::aeskey_check(&kek)),
+        argon2: argon2.clone(),
+        wrapped_key: Some(WrappedKey::wrap(&kek, &data_key)?),
+    };
+    // crypt/ is committed after config.toml is saved
+    let staged = rewrap_crypt_dirs(dirpath, old_argon2, &old_kek, &argon2, &kek)?;
+    config.crypt = config.crypt.with_key(new_pk);
+    let secrets = saved.then_some(Secrets { argon2, key: kek });
+
+    Ok((config, staged, secrets))
+}
+
+/// Change the passphrase and save config.toml, then crypt/ and the secrets file.
+///
+/// If config.toml cannot be saved, nothing is changed.
+fn process_change_passphrase(
+    dirpath: &Path,
+    old_source: &PassphraseSource,
+    source: &PassphraseSource,
+) -> Result<()> {
+    let _lock = super::lock_config(dirpath)?;
+    let config = super::read_config(&dirpath.join(super::CONFIG_FILE_NAME))?;
+    clean_rewrap(dirpath, &config)?;
+
+    let (mut config, staged, secrets) = change_passphrase(dirpath, config, old_source, source)?;
+    config.system.update();
+    if let Err(err) = super::save_config(dirpath, &config) {
+        discard_rewrap(&staged);
+        return Err(err);
+    }
+    // the secrets file follows config.toml even if some metadata is not replaced
+    // (left ones are committed by the next run, see [clean_rewrap])
+    let res = commit_rewrap(&staged);
+    if let Some(secrets) = secrets {
+        super::write_secrets(dirpath, Some(&secrets))?;
+    }
+    res?;
+    info!("Passphrase changed: {}", config.crypt);
+
+    Ok(())
+}
+
+fn process_key(
+    dirpath: &Path,
+    config: Config,
+    ctype: Option<&str>,
+    save: bool,
+    source: &PassphraseSource,
+    recipients: &[String],
+) -> Result<Option<Config>> {
     if let Some(ctype) = ctype {
         let ctype = CryptType::from_str(ctype).with_context(|| {
             info!("{}", crypt_type_help());
             format!("Invalid crypt type - {ctype}")
         })?;
         match ctype {
-            CryptType::PlainText => genkey_plaintext(config),
-            CryptType::Aes128GcmArgon2 { .. } => genkey_aes(config),
+            CryptType::PlainText => genkey_plaintext(dirpath, config),
+            CryptType::Aes128GcmArgon2(_) | CryptType::XChaCha20Poly1305Argon2(_) => {
+                genkey_passphrase(dirpath, config, ctype, save, source)
+            }
+            CryptType::X25519(_) => genkey_x25519(dirpath, config, recipients),
         }
     } else {
         // print status
         info!("Current status: {}", config.crypt);
+        if config.crypt.passphrase_key().is_some() {
+            if super::read_secrets(dirpath)?.is_some() {
+                info!("key   : SAVED in {SECRETS_FILE_NAME}");
+            } else {
+                info!("key   : NODATA (passphrase needed)");
+            }
+        }
         Ok(None)
     }
 }
@@ -78,22 +393,271 @@ fn crypt_type_help() -> String {
 }
 
 pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
-    const DESC: &str = "Show/Generate encryption key";
+    const DESC: &str = "Show/Generate encryption key or change the passphrase";
     const USAGE_HINT: &str = "--help or -h to show usage";
     let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help");
+    opts.optflag(
+        "",
+        "no-save",
+        "Do not save the derived key (passphrase is needed every time)",
+    );
+    opts.optmulti(
+        "r",
+        "recipient",
+        "Public key to encrypt to (x25519, can be specified multiple times)",
+        "<PUBKEY>",
+    );
+    PassphraseSource::add_options(&mut opts);
+    PassphraseSource::add_prefixed_options(&mut opts, super::OLD_PASSPHRASE_PREFIX);
 
     if util::find_option(&args, &["-h", "--help"]) {
-        println!("{}", util::create_help(cmd, DESC, &opts, Some("[TYPE]")));
+        println!(
+            "{}",
+            util::create_help(
+                cmd,
+                DESC,
+                &opts,
+                Some(&format!(
+                    "[TYPE | {ACTION_CHANGE_PASSPHRASE} | {ACTION_GEN_KEYPAIR} KEY_FILE]"
+                ))
+            )
+        );
         println!("{}", crypt_type_help());
+        println!(
+            "{ACTION_CHANGE_PASSPHRASE}\n  Re-wrap the data key with a new passphrase (no re-encryption)\n  \
+             The current one is from the saved key, --old-passphrase-* or ${}",
+            super::OLD_PASSPHRASE_ENV
+        );
+        println!(
+            "{ACTION_GEN_KEYPAIR}\n  Write a new x25519 private key to KEY_FILE and print the public key"
+        );
         return Ok(());
     }
     let matches = opts.parse(args).context(USAGE_HINT)?;
 
-    ensure!(matches.free.len() < 2, "Too much arguments");
     let ctype = matches.free.first().map(|s| s.as_str());
+    if ctype == Some(ACTION_GEN_KEYPAIR) {
+        // repository is not needed
+        ensure!(matches.free.len() == 2, "KEY_FILE is required");
+        return gen_keypair(Path::new(&matches.free[1]));
+    }
+    ensure!(matches.free.len() < 2, "Too much arguments");
+    let save = !matches.opt_present("no-save");
+    let source = PassphraseSource::from_matches(&matches)?;
+    let recipients = matches.opt_strs("r");
+    if ctype == Some(ACTION_CHANGE_PASSPHRASE) {
+        let old_source = PassphraseSource::from_prefixed_matches(
+            &matches,
+            super::OLD_PASSPHRASE_PREFIX,
+            super::OLD_PASSPHRASE_ENV,
+        )?;
+        return process_change_passphrase(basedir, &old_source, &source);
+    }
+
+    super::process_with_config_lock(basedir, |dirpath, config| {
+        process_key(dirpath, config, ctype, save, &source, &recipients)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use super::super::CryptInfo;
+    use super::*;
+    use tempdir::TempDir;
 
-    super::process_with_config_lock(basedir, |_dirpath, config| process_key(config, ctype))
+    fn new_param(password: &str) -> (Aes128GcmArgon2Param, AesKey) {
+        let (salt, m_cost, t_cost, p_cost, key) = cryptutil::aeskey_new_from_password(password);
+        let param = Aes128GcmArgon2Param {
+            salt,
+            m_cost,
+            t_cost,
+            p_cost,
+        };
+
+        (param, key)
+    }
+
+    #[test]
+    fn test_rewrap_crypt_dirs() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let crypt_path = dirpath.join(super::super::DIRNAME_CRYPT);
+
+        let (old_argon2, old_kek) = new_param("old");
+        let (other_argon2, _) = new_param("other");
+        let (new_argon2, new_kek) = new_param("new");
+        let data_key: AesKey = cryptutil::generate_random();
+
+        // a: wrapped, b: old format, c: another passphrase
+        let infos = [
+            (
+                "a",
+                &old_argon2,
+                Some(WrappedKey::wrap(&old_kek, &data_key)?),
+            ),
+            ("b", &old_argon2, None),
+            ("c", &other_argon2, None),
+        ];
+        for (tag, argon2, wrapped_key) in infos {
+            let info = CryptInfo {
+                crypt: CryptType::Aes128GcmArgon2(PassphraseKey {
+                    key_check: None,
+                    argon2: argon2.clone(),
+                    wrapped_key,
+                }),
+                total_size: 0,
+                fragment_size: NonZeroU64::new(1024).unwrap(),
+                aad: true,
+                header_version: cryptutil::FRAGMENT_VERSION,
+                compression: None,
+                parity: None,
+                chunks: None,
+            };
+            std::fs::create_dir_all(crypt_path.join(tag))?;
+            std::fs::write(
+                crypt_path.join(tag).join(super::super::CRYPT_INFO_NAME),
+                toml::to_string(&info)?,
+            )?;
+        }
+
+        let staged = rewrap_crypt_dirs(dirpath, &old_argon2, &old_kek, &new_argon2, &new_kek)?;
+        assert_eq!(staged.len(), 2);
+        commit_rewrap(&staged)?;
+
+        let unwrap = |tag: &str| -> Result<(Aes128GcmArgon2Param, Option<AesKey>)> {
+            let info = restore::read_crypt_info(&crypt_path.join(tag))?;
+            let CryptType::Aes128GcmArgon2(PassphraseKey {
+                argon2,
+                wrapped_key,
+                ..
+            }) = info.crypt
+            else {
+                panic!();
+            };
+            let key = wrapped_key.map(|w| w.unwrap(&new_kek)).transpose()?;
+            Ok((argon2, key))
+        };
+        assert_eq!(unwrap("a")?, (new_argon2.clone(), Some(data_key)));
+        assert_eq!(unwrap("b")?, (new_argon2.clone(), Some(old_kek)));
+        assert_eq!(unwrap("c")?, (other_argon2, None));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_passphrase() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let crypt_dir = dirpath
+            .join(super::super::DIRNAME_CRYPT)
+            .join("a")
+            .join("a_20240101.bin");
+        let info_path = crypt_dir.join(super::super::CRYPT_INFO_NAME);
+        let tomlpath = dirpath.join(super::super::CONFIG_FILE_NAME);
+
+        // the old key is not saved
+        let (old_argon2, old_kek) = new_param("old");
+        let data_key: AesKey = cryptutil::generate_random();
+        let pk = PassphraseKey {
+            key_check: Some(cryptutil::aeskey_check(&old_kek)),
+            argon2: old_argon2.clone(),
+            wrapped_key: Some(WrappedKey::wrap(&old_kek, &data_key)?),
+        };
+        let config = Config {
+            crypt: CryptType::Aes128GcmArgon2(pk.clone()),
+            ..Default::default()
+        };
+        std::fs::write(&tomlpath, toml::to_string(&config)?)?;
+        let info = CryptInfo {
+            crypt: CryptType::Aes128GcmArgon2(PassphraseKey {
+                key_check: None,
+                ..pk
+            }),
+            total_size: 0,
+            fragment_size: NonZeroU64::new(1024).unwrap(),
+            aad: true,
+            header_version: cryptutil::FRAGMENT_VERSION,
+            compression: None,
+            parity: None,
+            chunks: None,
+        };
+        std::fs::create_dir_all(&crypt_dir)?;
+        std::fs::write(&info_path, toml::to_string(&info)?)?;
+
+        let write_pass = |name: &str, pass: &str| -> Result<PassphraseSource> {
+            let path = dirpath.join(name);
+            std::fs::write(&path, pass)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            Ok(PassphraseSource::File(path))
+        };
+        let old_source = write_pass("old", "old")?;
+        let source = write_pass("new", "new")?;
+        let config_argon2 = || -> Result<Aes128GcmArgon2Param> {
+            let config = super::super::read_config(&tomlpath)?;
+            Ok(config.crypt.passphrase_key().unwrap().argon2.clone())
+        };
+        let info_argon2 = || -> Result<Aes128GcmArgon2Param> {
+            let info = restore::read_crypt_info(&crypt_dir)?;
+            Ok(info.crypt.passphrase_key().unwrap().argon2.clone())
+        };
+
+        // wrong old passphrase
+        let wrong = write_pass("wrong", "wrong")?;
+        assert!(process_change_passphrase(dirpath, &wrong, &source).is_err());
+
+        // config.toml cannot be saved: crypt/ is not changed
+        let tmp_path = dirpath.join("config.toml.tmp");
+        std::fs::create_dir(&tmp_path)?;
+        assert!(process_change_passphrase(dirpath, &old_source, &source).is_err());
+        assert_eq!(config_argon2()?, old_argon2);
+        assert_eq!(info_argon2()?, old_argon2);
+        assert!(!rewrap_path(&info_path).exists());
+        std::fs::remove_dir(&tmp_path)?;
+
+        process_change_passphrase(dirpath, &old_source, &source)?;
+        let new_argon2 = config_argon2()?;
+        assert_ne!(new_argon2, old_argon2);
+        assert_eq!(info_argon2()?, new_argon2);
+        assert!(!rewrap_path(&info_path).exists());
+        let new_kek = cryptutil::aeskey_from_password(
+            new_argon2.salt,
+            new_argon2.m_cost,
+            new_argon2.t_cost,
+            new_argon2.p_cost,
+            "new",
+        )?;
+        let info = restore::read_crypt_info(&crypt_dir)?;
+        let wrapped = info.crypt.passphrase_key().unwrap().wrapped_key.clone();
+        assert_eq!(wrapped.unwrap().unwrap(&new_kek)?, data_key);
+
+        // interrupted before saving config.toml: the staged one is deleted
+        let source2 = write_pass("new2", "new2")?;
+        let config = super::super::read_config(&tomlpath)?;
+        change_passphrase(dirpath, config, &source, &source2)?;
+        assert!(rewrap_path(&info_path).exists());
+        super::super::process_with_config_lock(dirpath, |_, _| Ok(None))?;
+        assert!(!rewrap_path(&info_path).exists());
+        assert_eq!(info_argon2()?, new_argon2);
+
+        // interrupted after saving config.toml: the staged one is committed
+        let config = super::super::read_config(&tomlpath)?;
+        let (config, _staged, _) = change_passphrase(dirpath, config, &source, &source2)?;
+        super::super::save_config(dirpath, &config)?;
+        assert_eq!(info_argon2()?, new_argon2);
+        super::super::process_with_config_lock(dirpath, |_, _| Ok(None))?;
+        assert!(!rewrap_path(&info_path).exists());
+        assert_ne!(config_argon2()?, new_argon2);
+        assert_eq!(info_argon2()?, config_argon2()?);
+
+        Ok(())
+    }
 }
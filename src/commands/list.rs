@@ -0,0 +1,312 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use getopts::Options;
+use log::{info, warn};
+use serde::Serialize;
+use strum::EnumMessage;
+
+use super::{restore, Config};
+use crate::util;
+
+#[derive(Debug, Serialize)]
+struct ListReport {
+    tags: Vec<TagReport>,
+    /// bytes on disk
+    repo_size: u64,
+    /// bytes on disk
+    crypt_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct TagReport {
+    tag: String,
+    /// newest first
+    versions: Vec<VersionReport>,
+    /// Versions stored in crypt/tag/
+    crypt: Vec<CryptReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct VersionReport {
+    name: String,
+    date: Option<String>,
+    /// None if the file is not found
+    size: Option<u64>,
+    crypt: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct CryptReport {
+    name: String,
+    crypt_type: String,
+    fragment_count: u64,
+    /// Referenced chunks in crypt/chunks/ (chunked layout)
+    chunk_count: Option<usize>,
+    total_size: u64,
+    /// bytes on disk
+    disk_size: u64,
+}
+
+fn crypt_report(crypt_path: &Path, tag: &str) -> Result<Vec<CryptReport>> {
+    let mut reports = Vec::new();
+    // newest first
+    for name in restore::crypt_versions(&crypt_path.join(tag))?
+        .into_iter()
+        .rev()
+    {
+        let crypt_dir_path = restore::find_crypt_dir(crypt_path, tag, &name)
+            .ok_or_else(|| anyhow!("Crypt data not found: {tag}/{name}"))?;
+        reports.push(crypt_version_report(&crypt_dir_path, name)?);
+    }
+
+    Ok(reports)
+}
+
+fn crypt_version_report(crypt_dir_path: &Path, name: String) -> Result<CryptReport> {
+    let info = restore::read_crypt_info(crypt_dir_path)?;
+    let crypt_type = info.crypt.get_message().unwrap_or_default().to_string();
+
+    Ok(CryptReport {
+        name,
+        crypt_type,
+        fragment_count: info.fragment_count(),
+        chunk_count: info.chunks.as_ref().map(|chunks| chunks.refs.len()),
+        total_size: info.total_size,
+        disk_size: util::dir_size(crypt_dir_path)?,
+    })
+}
+
+fn create_report(dirpath: &Path, config: &Config) -> Result<ListReport> {
+    let repo_path = dirpath.join(super::DIRNAME_REPO);
+    let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
+
+    let mut tags = Vec::new();
+    for (tag, ents) in config.repository.entries.iter() {
+        let versions = ents
+            .iter()
+            .map(|rf| {
+                let rf = &rf.0;
+                let date = super::split_filename(&rf.name)
+                    .and_then(|(_, date, _)| super::parse_date(date))
+                    .ok()
+                    .map(|date| date.to_string());
+                let size = repo_path
+                    .join(tag)
+                    .join(&rf.name)
+                    .metadata()
+                    .ok()
+                    .map(|meta| meta.len());
+                VersionReport {
+                    name: rf.name.clone(),
+                    date,
+                    size,
+                    crypt: rf.crypt,
+                }
+            })
+            .collect();
+
+        let crypt = match crypt_report(&crypt_path, tag) {
+            Ok(crypt) => crypt,
+            Err(err) => {
+                warn!("[{tag}] {:#}", err);
+                Vec::new()
+            }
+        };
+
+        tags.push(TagReport {
+            tag: tag.clone(),
+            versions,
+            crypt,
+        });
+    }
+
+    Ok(ListReport {
+        tags,
+        repo_size: util::dir_size(&repo_path)?,
+        crypt_size: util::dir_size(&crypt_path)?,
+    })
+}
+
+fn print_report(report: &ListReport) {
+    for tag in report.tags.iter() {
+        println!("[{}]", tag.tag);
+        for ver in tag.versions.iter() {
+            let size = ver
+                .size
+                .map(util::size_to_human_readable)
+                .unwrap_or("NOT FOUND".to_string());
+            println!(
+                "  {:<19}  {:>10}  {:<5}  {}",
+                ver.date.as_deref().unwrap_or("-"),
+                size,
+                if ver.crypt { "crypt" } else { "-" },
+                ver.name
+            );
+        }
+        for crypt in tag.crypt.iter() {
+            let count = match crypt.chunk_count {
+                Some(count) => format!("{count} chunks"),
+                None => format!("{} fragments", crypt.fragment_count),
+            };
+            println!(
+                "  crypt/: {} ({}, {}, {})",
+                crypt.name,
+                crypt.crypt_type,
+                count,
+                util::size_to_human_readable(crypt.disk_size)
+            );
+        }
+    }
+    println!("Tags  : {}", report.tags.len());
+    println!("repo/ : {}", util::size_to_human_readable(report.repo_size));
+    println!(
+        "crypt/: {}",
+        util::size_to_human_readable(report.crypt_size)
+    );
+}
+
+fn process_list(dirpath: &Path, config: &Config, json: Option<Option<String>>) -> Result<()> {
+    let report = create_report(dirpath, config)?;
+
+    match json {
+        None => print_report(&report),
+        Some(None) => println!("{}", serde_json::to_string_pretty(&report)?),
+        Some(Some(path)) => {
+            std::fs::write(&path, serde_json::to_string_pretty(&report)?)
+                .with_context(|| format!("Cannot write {path}"))?;
+            info!("Write OK: {path}");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str = "List files in the repository.";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optflagopt(
+        "j",
+        "json",
+        "Output in JSON format (into FILE if specified)",
+        "FILE",
+    );
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, None));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let json = if matches.opt_present("j") {
+        Some(matches.opt_str("j"))
+    } else {
+        None
+    };
+
+    super::process_with_config_lock(basedir, |dirpath, config| {
+        process_list(dirpath, &config, json)?;
+        Ok(None)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use super::super::{CryptInfo, RepositoryFile};
+    use super::*;
+    use tempdir::TempDir;
+
+    fn rf(name: &str, crypt: bool) -> Reverse<RepositoryFile> {
+        Reverse(RepositoryFile {
+            name: name.to_string(),
+            md5name: format!("{name}.md5sum"),
+            crypt,
+        })
+    }
+
+    #[test]
+    fn test_create_report() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tagdir = dirpath.join(super::super::DIRNAME_REPO).join("a");
+        std::fs::create_dir_all(&tagdir)?;
+        std::fs::write(tagdir.join("a_20240101.bin"), "hello")?;
+        std::fs::write(tagdir.join("a_20240102.bin"), "hello world")?;
+
+        // crypt/a/a_20240102.bin/
+        let cryptdir = dirpath
+            .join(super::super::DIRNAME_CRYPT)
+            .join("a")
+            .join("a_20240102.bin");
+        std::fs::create_dir_all(&cryptdir)?;
+        let info = toml::to_string(&CryptInfo {
+            crypt: Default::default(),
+            total_size: 11,
+            fragment_size: 8.try_into()?,
+            aad: false,
+            header_version: 0,
+            compression: None,
+            parity: None,
+            chunks: None,
+        })?;
+        std::fs::write(cryptdir.join(super::super::CRYPT_INFO_NAME), &info)?;
+        std::fs::write(cryptdir.join("a_20240102.bin.000000"), "hello wo")?;
+        std::fs::write(cryptdir.join("a_20240102.bin.000001"), "rld")?;
+
+        let mut config = Config::default();
+        config.repository.entries.insert(
+            "a".to_string(),
+            [
+                rf("a_20240101.bin", false),
+                rf("a_20240102.bin", true),
+                // lost
+                rf("a_20240103.bin", false),
+            ]
+            .into(),
+        );
+
+        let report = create_report(dirpath, &config)?;
+        assert_eq!(report.repo_size, 5 + 11);
+        assert_eq!(report.crypt_size, info.len() as u64 + 8 + 3);
+        assert_eq!(report.tags.len(), 1);
+        let tag = &report.tags[0];
+        let versions: Vec<_> = tag
+            .versions
+            .iter()
+            .map(|v| (v.name.as_str(), v.size, v.crypt))
+            .collect();
+        assert_eq!(
+            versions,
+            [
+                ("a_20240103.bin", None, false),
+                ("a_20240102.bin", Some(11), true),
+                ("a_20240101.bin", Some(5), false),
+            ]
+        );
+        assert_eq!(tag.versions[1].date.as_deref(), Some("2024-01-02 00:00:00"));
+        assert_eq!(tag.crypt.len(), 1);
+        assert_eq!(tag.crypt[0].name, "a_20240102.bin");
+        assert_eq!(tag.crypt[0].fragment_count, 2);
+        assert_eq!(tag.crypt[0].chunk_count, None);
+        assert_eq!(tag.crypt[0].total_size, 11);
+
+        // --json
+        let json = serde_json::to_value(&report)?;
+        assert_eq!(json["repo_size"], 16);
+        assert_eq!(json["tags"][0]["tag"], "a");
+        assert_eq!(
+            json["tags"][0]["versions"][0]["size"],
+            serde_json::Value::Null
+        );
+        assert_eq!(json["tags"][0]["versions"][1]["crypt"], true);
+        assert_eq!(json["tags"][0]["crypt"][0]["name"], "a_20240102.bin");
+        assert_eq!(json["tags"][0]["crypt"][0]["fragment_count"], 2);
+
+        Ok(())
+    }
+}
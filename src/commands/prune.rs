@@ -0,0 +1,356 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, NaiveDateTime};
+use getopts::{Matches, Options};
+use log::{info, warn};
+
+use super::{restore, Config, RepositoryFile, RetentionPolicy};
+use crate::util;
+
+/// Select versions to be kept.
+///
+/// dates: newest first
+/// Return indices of dates.
+fn select_keep(policy: &RetentionPolicy, dates: &[NaiveDateTime]) -> BTreeSet<usize> {
+    let mut keep = BTreeSet::new();
+    if dates.is_empty() {
+        return keep;
+    }
+
+    // the latest is always kept
+    keep.insert(0);
+    for i in 0..(policy.last as usize).min(dates.len()) {
+        keep.insert(i);
+    }
+
+    // keep the newest one in each period
+    let mut keep_period = |count: u32, period: fn(&NaiveDateTime) -> (i32, u32)| {
+        let mut last = None;
+        let mut kept = 0;
+        for (i, dt) in dates.iter().enumerate() {
+            if kept >= count {
+                break;
+            }
+            let cur = period(dt);
+            if last != Some(cur) {
+                keep.insert(i);
+                kept += 1;
+                last = Some(cur);
+            }
+        }
+    };
+    keep_period(policy.daily, |dt| (dt.year(), dt.ordinal()));
+    keep_period(policy.weekly, |dt| {
+        let week = dt.iso_week();
+        (week.year(), week.week())
+    });
+    keep_period(policy.monthly, |dt| (dt.year(), dt.month()));
+    keep_period(policy.yearly, |dt| (dt.year(), 0));
+
+    keep
+}
+
+/// Return files to be removed.
+fn select_remove(
+    policy: &RetentionPolicy,
+    ents: &BTreeSet<Reverse<RepositoryFile>>,
+) -> Vec<RepositoryFile> {
+    // files with unknown date are always kept
+    let mut dated = Vec::new();
+    for rf in ents.iter().map(|rf| &rf.0) {
+        let date = super::split_filename(&rf.name).and_then(|(_, date, _)| super::parse_date(date));
+        match date {
+            Ok(date) => dated.push((date, rf)),
+            Err(err) => warn!("Keep (unknown date): {}: {:#}", rf.name, err),
+        }
+    }
+    // newest first
+    dated.sort_by_key(|(date, _)| Reverse(*date));
+
+    let dates: Vec<_> = dated.iter().map(|(date, _)| *date).collect();
+    let keep = select_keep(policy, &dates);
+
+    dated
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !keep.contains(i))
+        .map(|(_, (_, rf))| rf.clone())
+        .collect()
+}
+
+fn remove_file(tag_path: &Path, rf: &RepositoryFile) -> Result<()> {
+    for name in [&rf.name, &rf.md5name] {
+        let path = tag_path.join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => info!("Delete OK: {}", path.display()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!("Already deleted: {}", path.display())
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Delete failed: {}", path.display()))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove crypt/tag/name (or the files of name in crypt/tag of the old layout).
+fn remove_crypt_version(crypt_path: &Path, tag: &str, name: &str) -> Result<()> {
+    let Some(path) = restore::find_crypt_dir(crypt_path, tag, name) else {
+        return Ok(());
+    };
+
+    if path != crypt_path.join(tag) {
+        std::fs::remove_dir_all(&path)
+            .with_context(|| format!("Delete failed: {}", path.display()))?;
+        info!("Delete OK: {}", path.display());
+        return Ok(());
+    }
+
+    // old layout: newer versions are in subdirectories
+    let prefix = format!("{name}.");
+    let info_path = path.join(super::CRYPT_INFO_NAME);
+    std::fs::remove_file(&info_path)
+        .with_context(|| format!("Delete failed: {}", info_path.display()))?;
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if entry.path().is_file() && file_name.to_string_lossy().starts_with(&prefix) {
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("Delete failed: {}", entry.path().display()))?;
+        }
+    }
+    info!("Delete OK: {} ({name})", path.display());
+
+    Ok(())
+}
+
+fn process_prune(
+    dirpath: &Path,
+    mut config: Config,
+    matches: &Matches,
+    dry_run: bool,
+) -> (Option<Config>, Result<()>) {
+    let repo_path = dirpath.join(super::DIRNAME_REPO);
+    let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
+
+    // tag config or repository-wide config, and then command line
+    let mut policies = BTreeMap::new();
+    for tag in config.repository.entries.keys() {
+        let policy = config.retention_for(tag).clone();
+        // already checked
+        let policy = policy_from_args(matches, policy).unwrap();
+        policies.insert(tag.clone(), policy);
+    }
+
+    let mut removed = 0;
+    let mut failed = 0;
+    // crypt dirs which would be removed (dry run)
+    let mut removing: BTreeSet<PathBuf> = BTreeSet::new();
+    for (tag, ents) in config.repository.entries.iter_mut() {
+        let policy = &policies[tag];
+        if policy.is_empty() {
+            info!("[{tag}] Skip (retention policy is not set)");
+            continue;
+        }
+        info!("[{tag}] Retention policy: {:?}", policy);
+
+        let remove_list = select_remove(policy, ents);
+        info!(
+            "[{tag}] Keep: {}, Remove: {}",
+            ents.len() - remove_list.len(),
+            remove_list.len()
+        );
+        for rf in remove_list {
+            if dry_run {
+                info!("[{tag}] Remove (dry run): {}", rf.name);
+                removing.extend(restore::find_crypt_dir(&crypt_path, tag, &rf.name));
+                continue;
+            }
+            // crypt data first (the entry is kept if failed)
+            let result = remove_crypt_version(&crypt_path, tag, &rf.name)
+                .and_then(|()| remove_file(&repo_path.join(tag), &rf));
+            match result {
+                Ok(()) => {
+                    ents.remove(&Reverse(rf));
+                    removed += 1;
+                }
+                Err(err) => {
+                    warn!("{:#}", err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    // chunks which no longer belong to any version in crypt/
+    match super::chunk::remove_unreferenced(&crypt_path, &removing, dry_run) {
+        Ok((count, size)) => info!("Unreferenced chunks: {count} ({size} bytes)"),
+        Err(err) => {
+            warn!("{:#}", err);
+            failed += 1;
+        }
+    }
+
+    if dry_run {
+        return (None, Ok(()));
+    }
+    info!("Removed: {}", removed);
+    info!("Failed : {}", failed);
+
+    config.system.update();
+    let res = if failed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("One or more errors occurred"))
+    };
+
+    (Some(config), res)
+}
+
+/// Override config by command line.
+fn policy_from_args(matches: &Matches, mut policy: RetentionPolicy) -> Result<RetentionPolicy> {
+    for (opt, value) in [
+        ("keep-last", &mut policy.last),
+        ("keep-daily", &mut policy.daily),
+        ("keep-weekly", &mut policy.weekly),
+        ("keep-monthly", &mut policy.monthly),
+        ("keep-yearly", &mut policy.yearly),
+    ] {
+        if let Some(n) = matches.opt_get(opt)? {
+            *value = n;
+        }
+    }
+
+    Ok(policy)
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str =
+        "Remove old files in repo/ and crypt/ by retention policy (tag config, config or \
+         command line) and unreferenced chunks in crypt/chunks/.";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optflag("n", "dry-run", "Print files to be removed only");
+    opts.optopt("", "keep-last", "Keep the last N versions", "N");
+    opts.optopt("", "keep-daily", "Keep the last N daily versions", "N");
+    opts.optopt("", "keep-weekly", "Keep the last N weekly versions", "N");
+    opts.optopt("", "keep-monthly", "Keep the last N monthly versions", "N");
+    opts.optopt("", "keep-yearly", "Keep the last N yearly versions", "N");
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, None));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let dry_run = matches.opt_present("n");
+    // check format
+    policy_from_args(&matches, Default::default())?;
+
+    super::process_with_config_lock_force_save(basedir, |dirpath, config| {
+        process_prune(dirpath, config, &matches, dry_run)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dates(list: &[&str]) -> Vec<NaiveDateTime> {
+        list.iter()
+            .map(|s| super::super::parse_date(s).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_select_keep_last() {
+        let dates = dates(&["20240105", "20240104", "20240103", "20240102"]);
+
+        let policy = RetentionPolicy {
+            last: 2,
+            ..Default::default()
+        };
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0, 1]));
+
+        // the latest is always kept
+        let policy = RetentionPolicy::default();
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0]));
+
+        assert!(select_keep(&policy, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_select_keep_gfs() {
+        let dates = dates(&[
+            "20240302120000",
+            "20240302000000",
+            "20240301",
+            "20240215",
+            "20240131",
+            "20240101",
+            "20231231",
+            "20230101",
+        ]);
+
+        let policy = RetentionPolicy {
+            daily: 2,
+            ..Default::default()
+        };
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0, 2]));
+
+        let policy = RetentionPolicy {
+            monthly: 3,
+            ..Default::default()
+        };
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0, 3, 4]));
+
+        let policy = RetentionPolicy {
+            yearly: 10,
+            ..Default::default()
+        };
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0, 6]));
+
+        // 2024-03-02 (Sat) and 2024-03-01 (Fri) are in the same week
+        let policy = RetentionPolicy {
+            weekly: 2,
+            ..Default::default()
+        };
+        assert_eq!(select_keep(&policy, &dates), BTreeSet::from([0, 3]));
+    }
+
+    #[test]
+    fn test_select_remove() {
+        let ents: BTreeSet<_> = [
+            "a_20240103.bin",
+            "a_20240102.bin",
+            "a_20240101.bin",
+            "a.bin",
+        ]
+        .iter()
+        .map(|name| {
+            Reverse(RepositoryFile {
+                name: name.to_string(),
+                md5name: format!("{name}.md5sum"),
+                crypt: false,
+            })
+        })
+        .collect();
+        let policy = RetentionPolicy {
+            last: 2,
+            ..Default::default()
+        };
+        let removed: Vec<_> = select_remove(&policy, &ents)
+            .into_iter()
+            .map(|rf| rf.name)
+            .collect();
+        assert_eq!(removed, ["a_20240101.bin"]);
+    }
+}
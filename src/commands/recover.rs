@@ -0,0 +1,191 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use getopts::Options;
+use log::{error, info};
+
+use super::{restore, PassphraseSource};
+use crate::util;
+
+/// Find "name" from "name.000000", "name.000001", ...
+///
+/// Return None if there is no fragment.
+pub(super) fn find_fragment_name(src_dir_path: &Path) -> Result<Option<String>> {
+    let mut names = BTreeSet::new();
+    for entry in src_dir_path
+        .read_dir()
+        .with_context(|| format!("Cannot read dir: {}", src_dir_path.display()))?
+    {
+        let entry = entry?;
+        let filename = entry.file_name();
+        let Some(filename) = filename.to_str() else {
+            continue;
+        };
+        if let Some((name, idx)) = filename.rsplit_once('.') {
+            // "name.000000" or "name.manifest" (chunked)
+            if (idx.len() == 6 && idx.bytes().all(|b| b.is_ascii_digit()))
+                || idx == super::MANIFESTEXT
+            {
+                names.insert(name.to_string());
+            }
+        }
+    }
+
+    ensure!(
+        names.len() <= 1,
+        "Fragments of multiple files found: {:?}",
+        names
+    );
+
+    Ok(names.pop_first())
+}
+
+fn process_recover(
+    src_dir_path: &Path,
+    dst_path: &Path,
+    tag: Option<&str>,
+    source: PassphraseSource,
+    identity: Option<PathBuf>,
+) -> Result<()> {
+    let info = restore::read_crypt_info(src_dir_path)?;
+    info!(
+        "Fragment count: {} (total {} bytes, fragment {} bytes)",
+        info.fragment_count(),
+        info.total_size,
+        info.fragment_size
+    );
+
+    let name = find_fragment_name(src_dir_path)?;
+    let name = match name {
+        Some(name) => name,
+        None if info.fragment_count() == 0 => String::new(),
+        None => bail!("No fragment found: {}", src_dir_path.display()),
+    };
+    info!("Fragment name: {name}");
+
+    // the original crypt/<tag> name is needed to authenticate fragments
+    // (crypt/<tag>/<name>, or crypt/<tag> in the old layout)
+    let tag = match tag {
+        Some(tag) => tag.to_string(),
+        None => {
+            let dir_path = src_dir_path.canonicalize()?;
+            let tag_path = if dir_path.file_name() == Some(name.as_ref()) {
+                dir_path.parent()
+            } else {
+                Some(dir_path.as_path())
+            };
+            tag_path
+                .and_then(|path| path.file_name())
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("Cannot get tag from {}", src_dir_path.display()))?
+                .to_string()
+        }
+    };
+    if info.aad {
+        info!("Tag: {tag}");
+    }
+
+    let problems = restore::check_fragments(src_dir_path, &name, &info);
+    for problem in problems.iter() {
+        error!("{problem}");
+    }
+    ensure!(problems.is_empty(), "{} problem(s) found", problems.len());
+
+    let mut keys = restore::KeyCache::new(source);
+    keys.set_identity(identity);
+
+    let md5 = restore::decrypt_to_file(
+        src_dir_path,
+        &tag,
+        &name,
+        &info,
+        &mut keys,
+        dst_path,
+        |_| Ok(()),
+    )
+    .with_context(|| {
+        if info.aad {
+            format!("Tag: {tag} (--tag is needed if CRYPT_DIR was renamed)")
+        } else {
+            "Recover failed".to_string()
+        }
+    })?;
+    info!("Write OK: {}", dst_path.display());
+
+    let md5str = util::md5_to_str(&md5);
+    let mut dst_md5_path = dst_path.as_os_str().to_owned();
+    dst_md5_path.push(format!(".{}", super::MD5EXT));
+    std::fs::write(&dst_md5_path, &md5str)
+        .with_context(|| format!("Cannot write {}", dst_md5_path.to_string_lossy()))?;
+    info!("Write OK: {} ({md5str})", dst_md5_path.to_string_lossy());
+
+    Ok(())
+}
+
+pub fn entry(_basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str =
+        "Restore a file from a crypt/<tag>/<name> directory only (config file is not needed).";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optopt(
+        "t",
+        "tag",
+        "The original tag name (default=the parent of CRYPT_DIR, or CRYPT_DIR name in the old layout)",
+        "<TAG>",
+    );
+    PassphraseSource::add_options(&mut opts);
+    super::add_identity_option(&mut opts);
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!(
+            "{}",
+            util::create_help(cmd, DESC, &opts, Some("CRYPT_DIR OUT_FILE"))
+        );
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+
+    ensure!(
+        matches.free.len() == 2,
+        "CRYPT_DIR and OUT_FILE are required"
+    );
+    let src_dir_path = Path::new(&matches.free[0]);
+    let dst_path = Path::new(&matches.free[1]);
+    let tag = matches.opt_str("t");
+    let source = PassphraseSource::from_matches(&matches)?;
+    let identity = matches.opt_str("i").map(PathBuf::from);
+
+    process_recover(src_dir_path, dst_path, tag.as_deref(), source, identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_find_fragment_name() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        std::fs::write(dirpath.join(super::super::CRYPT_INFO_NAME), "")?;
+        assert_eq!(find_fragment_name(dirpath)?, None);
+
+        std::fs::write(dirpath.join("a_20240101.tar.000000"), "")?;
+        std::fs::write(dirpath.join("a_20240101.tar.000001"), "")?;
+        std::fs::write(dirpath.join("a_20240101.tar.00002"), "")?;
+        assert_eq!(
+            find_fragment_name(dirpath)?,
+            Some("a_20240101.tar".to_string())
+        );
+
+        std::fs::write(dirpath.join("b_20240101.tar.000000"), "")?;
+        assert!(find_fragment_name(dirpath).is_err());
+
+        Ok(())
+    }
+}
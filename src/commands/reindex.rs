@@ -0,0 +1,268 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use getopts::Options;
+use log::{info, warn};
+
+use super::{restore, Config, Repository, RepositoryFile};
+use crate::util;
+
+#[derive(Debug, Default)]
+struct ReindexReport {
+    /// "tag/name" on disk but not in the index
+    added: Vec<String>,
+    /// "tag/name" in the index but not on disk
+    removed: Vec<String>,
+    /// files on disk which cannot be indexed
+    orphans: Vec<String>,
+}
+
+/// Scan repo/tag/ and crypt/tag/ and create a new index.
+///
+/// The crypt flag of a file which is not in crypt/tag is taken from the old index.
+fn scan(dirpath: &Path, old: &Repository) -> Result<(Repository, ReindexReport)> {
+    let repo_path = dirpath.join(super::DIRNAME_REPO);
+    let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
+    let md5ext = format!(".{}", super::MD5EXT);
+
+    let mut repo = Repository::default();
+    let mut report = ReindexReport::default();
+
+    for entry in repo_path
+        .read_dir()
+        .with_context(|| format!("Cannot read dir: {}", repo_path.display()))?
+    {
+        let entry = entry?;
+        let tag_path = entry.path();
+        let Some(tag) = entry.file_name().to_str().map(|s| s.to_string()) else {
+            report.orphans.push(tag_path.display().to_string());
+            continue;
+        };
+        if !tag_path.is_dir() {
+            report.orphans.push(format!("{tag} (not a directory)"));
+            continue;
+        }
+
+        let crypt_names = match restore::crypt_versions(&crypt_path.join(&tag)) {
+            Ok(names) => names,
+            Err(err) => {
+                warn!("[{tag}] {:#}", err);
+                BTreeSet::new()
+            }
+        };
+
+        let mut names = BTreeSet::new();
+        for entry in tag_path.read_dir()? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(name) if entry.path().is_file() => {
+                    names.insert(name.to_string());
+                }
+                _ => report.orphans.push(entry.path().display().to_string()),
+            }
+        }
+
+        let mut ents = BTreeSet::new();
+        for name in names.iter() {
+            if let Some(data_name) = name.strip_suffix(&md5ext) {
+                if !names.contains(data_name) {
+                    report
+                        .orphans
+                        .push(format!("{tag}/{name} (data file not found)"));
+                }
+                continue;
+            }
+            match super::split_filename(name) {
+                Ok((file_tag, _, _)) if file_tag == tag => {}
+                Ok(_) => {
+                    report.orphans.push(format!("{tag}/{name} (tag unmatch)"));
+                    continue;
+                }
+                Err(err) => {
+                    report.orphans.push(format!("{tag}/{name} ({:#})", err));
+                    continue;
+                }
+            }
+            let md5name = format!("{name}{md5ext}");
+            if !names.contains(&md5name) {
+                report.orphans.push(format!("{tag}/{name} (md5 not found)"));
+                continue;
+            }
+
+            let old_rf = old
+                .entries
+                .get(&tag)
+                .and_then(|ents| ents.iter().map(|rf| &rf.0).find(|rf| &rf.name == name));
+            let crypt = crypt_names.contains(name) || old_rf.is_some_and(|rf| rf.crypt);
+            if old_rf.is_none() {
+                report.added.push(format!("{tag}/{name}"));
+            }
+            ents.insert(Reverse(RepositoryFile {
+                name: name.clone(),
+                md5name,
+                crypt,
+            }));
+        }
+
+        for name in crypt_names {
+            if !names.contains(&name) {
+                report
+                    .orphans
+                    .push(format!("crypt/{tag}/{name} (not found in repo/)"));
+            }
+        }
+        if !ents.is_empty() {
+            repo.entries.insert(tag, ents);
+        }
+    }
+
+    for (tag, ents) in old.entries.iter() {
+        for rf in ents.iter().map(|rf| &rf.0) {
+            let found = repo
+                .entries
+                .get(tag)
+                .is_some_and(|ents| ents.iter().any(|new| new.0.name == rf.name));
+            if !found {
+                report.removed.push(format!("{tag}/{}", rf.name));
+            }
+        }
+    }
+
+    Ok((repo, report))
+}
+
+fn process_reindex(dirpath: &Path, mut config: Config, dry_run: bool) -> Result<Option<Config>> {
+    let (repo, report) = scan(dirpath, &config.repository)?;
+
+    for name in report.added.iter() {
+        info!("Add: {name}");
+    }
+    for name in report.removed.iter() {
+        warn!("Remove (file not found): {name}");
+    }
+    for name in report.orphans.iter() {
+        warn!("Orphan: {name}");
+    }
+    info!(
+        "Added: {}, Removed: {}, Orphans: {}",
+        report.added.len(),
+        report.removed.len(),
+        report.orphans.len()
+    );
+
+    if dry_run {
+        return Ok(None);
+    }
+    config.repository = repo;
+    config.system.update();
+
+    Ok(Some(config))
+}
+
+/// Create config.toml with default settings if not exists.
+fn create_config_if_missing(dirpath: &Path) -> Result<()> {
+    let _lock = super::lock_config(dirpath)?;
+    let tomlpath = dirpath.join(super::CONFIG_FILE_NAME);
+    if !tomlpath.exists() {
+        warn!("Create (not found): {}", tomlpath.display());
+        let toml = toml::to_string(&Config::default()).unwrap();
+        util::write_atomic(&tomlpath, toml)
+            .with_context(|| format!("Cannot write {}", tomlpath.display()))?;
+    }
+
+    Ok(())
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str = "Rebuild the index in config.toml from repo/ and crypt/.";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optflag("n", "dry-run", "Print differences only");
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, None));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let dry_run = matches.opt_present("n");
+
+    if !dry_run {
+        create_config_if_missing(basedir)?;
+    }
+    super::process_with_config_lock(basedir, |dirpath, config| {
+        process_reindex(dirpath, config, dry_run)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn rf(name: &str, crypt: bool) -> Reverse<RepositoryFile> {
+        Reverse(RepositoryFile {
+            name: name.to_string(),
+            md5name: format!("{name}.md5sum"),
+            crypt,
+        })
+    }
+
+    #[test]
+    fn test_scan() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tagdir = dirpath.join(super::super::DIRNAME_REPO).join("a");
+        std::fs::create_dir_all(&tagdir)?;
+        for name in [
+            "a_20240101.bin",
+            "a_20240101.bin.md5sum",
+            "a_20240102.bin",
+            "a_20240102.bin.md5sum",
+            // orphans
+            "a_20240103.bin",
+            "a_20240104.bin.md5sum",
+            "b_20240101.bin",
+            "b_20240101.bin.md5sum",
+        ] {
+            std::fs::write(tagdir.join(name), "")?;
+        }
+        let cryptdir = dirpath.join(super::super::DIRNAME_CRYPT).join("a");
+        std::fs::create_dir_all(&cryptdir)?;
+        std::fs::write(
+            cryptdir.join(super::super::CRYPT_INFO_NAME),
+            toml::to_string(&super::super::CryptInfo {
+                crypt: Default::default(),
+                total_size: 0,
+                fragment_size: 1.try_into()?,
+                aad: false,
+                header_version: 0,
+                compression: None,
+                parity: None,
+                chunks: None,
+            })?,
+        )?;
+        std::fs::write(cryptdir.join("a_20240102.bin.000000"), "")?;
+
+        let mut old = Repository::default();
+        old.entries.insert(
+            "a".to_string(),
+            [rf("a_20240101.bin", false), rf("a_20231231.bin", true)].into(),
+        );
+
+        let (repo, report) = scan(dirpath, &old)?;
+        assert_eq!(
+            repo.entries["a"],
+            [rf("a_20240101.bin", false), rf("a_20240102.bin", true)].into()
+        );
+        assert_eq!(report.added, ["a/a_20240102.bin"]);
+        assert_eq!(report.removed, ["a/a_20231231.bin"]);
+        assert_eq!(report.orphans.len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use getopts::Options;
+use log::{error, info, warn};
+use md5::{Digest, Md5};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+
+use super::{restore, Config, CryptInfo, CryptType, ParityInfo};
+use crate::util;
+
+/// Bytes of each shard processed at once
+pub(super) const STRIPE_SIZE: usize = 1024 * 1024;
+/// Limit of GF(2^8)
+const SHARDS_MAX: usize = 256;
+
+/// Parse "N+K" (N data fragments and K parity fragments per group).
+pub(super) fn parse_parity(s: &str) -> Result<(usize, usize)> {
+    let (data, parity) = s
+        .split_once('+')
+        .ok_or_else(|| anyhow!("Invalid parity (N+K expected): {s}"))?;
+    let data: usize = data
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid parity: {s}"))?;
+    let parity: usize = parity
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid parity: {s}"))?;
+    ensure!(
+        data >= 1 && parity >= 1 && data + parity <= SHARDS_MAX,
+        "Invalid parity (N >= 1, K >= 1, N + K <= {SHARDS_MAX}): {s}"
+    );
+
+    Ok((data, parity))
+}
+
+/// Data fragment indices and parity fragment indices of a group.
+fn group_range(parity: &ParityInfo, group: u64) -> (Range<u64>, Range<u64>) {
+    let count = parity.sizes.len() as u64;
+    let n = parity.data_shards as u64;
+    let k = parity.parity_shards as u64;
+    let data = (group * n)..count.min((group + 1) * n);
+    let parity = (group * k)..((group + 1) * k);
+
+    (data, parity)
+}
+
+fn group_count(parity: &ParityInfo) -> u64 {
+    (parity.sizes.len() as u64).div_ceil(parity.data_shards as u64)
+}
+
+/// Shard size of a group (the largest data fragment).
+fn shard_size(parity: &ParityInfo, data: &Range<u64>) -> u64 {
+    parity.sizes[data.start as usize..data.end as usize]
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0)
+}
+
+/// Expected size of each parity fragment.
+pub(super) fn parity_sizes(parity: &ParityInfo) -> Vec<u64> {
+    (0..group_count(parity))
+        .flat_map(|group| {
+            let (data, _) = group_range(parity, group);
+            std::iter::repeat_n(shard_size(parity, &data), parity.parity_shards)
+        })
+        .collect()
+}
+
+/// (path, stored size, MD5 string) of each shard in a group (data first).
+fn group_shards(
+    dir_path: &Path,
+    name: &str,
+    parity: &ParityInfo,
+    group: u64,
+) -> Vec<(PathBuf, u64, String)> {
+    let (data, pidx) = group_range(parity, group);
+    let shard_size = shard_size(parity, &data);
+    let data = data.map(|idx| {
+        (
+            dir_path.join(super::fragment_name(name, idx)),
+            parity.sizes[idx as usize],
+            parity.data_md5[idx as usize].clone(),
+        )
+    });
+    let pidx = pidx.map(|idx| {
+        (
+            dir_path.join(super::fragment_parity_name(name, idx)),
+            shard_size,
+            parity.parity_md5[idx as usize].clone(),
+        )
+    });
+
+    data.chain(pidx).collect()
+}
+
+/// Create parity fragments of "name.000000", ... (count files) in dir_path.
+///
+/// Return the metadata to be saved in [CryptInfo::parity].
+pub(super) async fn write_parity(
+    dir_path: &Path,
+    name: &str,
+    count: u64,
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<ParityInfo> {
+    let mut sizes = Vec::new();
+    for idx in 0..count {
+        let path = dir_path.join(super::fragment_name(name, idx));
+        sizes.push(tokio::fs::metadata(&path).await?.len());
+    }
+    let mut parity = ParityInfo {
+        data_shards,
+        parity_shards,
+        sizes,
+        data_md5: Vec::new(),
+        parity_md5: Vec::new(),
+    };
+
+    for group in 0..group_count(&parity) {
+        let (data, pidx) = group_range(&parity, group);
+        let shard_size = shard_size(&parity, &data);
+        let mut rs = ReedSolomon::new(data.clone().count(), parity_shards)?;
+
+        let mut fins = Vec::new();
+        for idx in data.clone() {
+            let path = dir_path.join(super::fragment_name(name, idx));
+            fins.push(tokio::fs::File::open(&path).await?);
+        }
+        let mut fouts = Vec::new();
+        for idx in pidx {
+            let path = dir_path.join(super::fragment_parity_name(name, idx));
+            fouts.push(tokio::fs::File::create(&path).await?);
+        }
+        let mut data_hashers = vec![Md5::new(); fins.len()];
+        let mut parity_hashers = vec![Md5::new(); fouts.len()];
+
+        let mut offset = 0u64;
+        while offset < shard_size {
+            let len = (shard_size - offset).min(STRIPE_SIZE as u64) as usize;
+            // zero-padded
+            let mut shards = vec![vec![0u8; len]; fins.len()];
+            for ((fin, shard), hasher) in fins.iter_mut().zip(&mut shards).zip(&mut data_hashers) {
+                let rsize = util::read_fully(fin, shard).await?;
+                hasher.update(&shard[..rsize]);
+            }
+            let parity_count = fouts.len();
+            let parity_bufs;
+            (rs, parity_bufs) = util::blocking(move || {
+                let mut parity_bufs = vec![vec![0u8; len]; parity_count];
+                rs.encode_sep(&shards, &mut parity_bufs)?;
+                Ok((rs, parity_bufs))
+            })
+            .await?;
+            for ((fout, buf), hasher) in fouts.iter_mut().zip(&parity_bufs).zip(&mut parity_hashers)
+            {
+                fout.write_all(buf).await?;
+                hasher.update(buf);
+            }
+            offset += len as u64;
+        }
+        for fout in fouts {
+            fout.sync_all().await?;
+        }
+
+        let md5s = |hashers: Vec<Md5>| hashers.into_iter().map(|h| util::md5_to_str(&h.finalize()));
+        parity.data_md5.extend(md5s(data_hashers));
+        parity.parity_md5.extend(md5s(parity_hashers));
+    }
+
+    Ok(parity)
+}
+
+/// Positions in the group (data first, then parity) of missing or modified files.
+async fn find_damaged(shards: &[(PathBuf, u64, String)]) -> Vec<usize> {
+    let mut damaged = Vec::new();
+    for (pos, (path, _size, md5str)) in shards.iter().enumerate() {
+        match util::md5_file(path).await {
+            Ok(md5) if util::md5_to_str(&md5) == *md5str => {}
+            _ => damaged.push(pos),
+        }
+    }
+
+    damaged
+}
+
+/// Reconstruct the damaged shards of a group.
+///
+/// Return the contents (stored size, MD5 checked) in the order of damaged.
+async fn reconstruct_group(
+    shards: &[(PathBuf, u64, String)],
+    data_shards: usize,
+    parity_shards: usize,
+    damaged: &[usize],
+) -> Result<Vec<Vec<u8>>> {
+    ensure!(
+        damaged.len() <= parity_shards,
+        "Too many damaged fragments in a group ({} > {parity_shards}): {}",
+        damaged.len(),
+        shards[damaged[0]].0.display()
+    );
+    let rs = ReedSolomon::new(data_shards, parity_shards)?;
+    // parity shard size
+    let shard_size = shards.last().unwrap().1;
+
+    let mut fins = Vec::new();
+    for (pos, (path, _size, _md5)) in shards.iter().enumerate() {
+        if damaged.contains(&pos) {
+            fins.push(None);
+        } else {
+            let file = tokio::fs::File::open(path)
+                .await
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+            fins.push(Some(file));
+        }
+    }
+    let mut outputs = vec![Vec::new(); damaged.len()];
+
+    let mut offset = 0u64;
+    while offset < shard_size {
+        let len = (shard_size - offset).min(STRIPE_SIZE as u64) as usize;
+        let mut stripe = Vec::new();
+        for fin in fins.iter_mut() {
+            match fin {
+                Some(fin) => {
+                    // zero-padded
+                    let mut buf = vec![0u8; len];
+                    util::read_fully(fin, &mut buf).await?;
+                    stripe.push(Some(buf));
+                }
+                None => stripe.push(None),
+            }
+        }
+        rs.reconstruct(&mut stripe)?;
+        for (output, &pos) in outputs.iter_mut().zip(damaged) {
+            output.extend_from_slice(stripe[pos].as_ref().unwrap());
+        }
+        offset += len as u64;
+    }
+
+    for (output, &pos) in outputs.iter_mut().zip(damaged) {
+        let (path, size, md5str) = &shards[pos];
+        output.truncate(*size as usize);
+        ensure!(
+            util::md5_to_str(&Md5::digest(&output)) == *md5str,
+            "Reconstruction failed (MD5 unmatch): {}",
+            path.display()
+        );
+    }
+
+    Ok(outputs)
+}
+
+/// Reconstruct "name.NNNNNN" in memory from the other fragments in its group.
+///
+/// Return None if the fragment file is intact (the cause is not damage).
+pub(super) async fn reconstruct_fragment(
+    dir_path: &Path,
+    name: &str,
+    info: &CryptInfo,
+    idx: u64,
+) -> Result<Option<Vec<u8>>> {
+    let parity = info
+        .parity
+        .as_ref()
+        .ok_or_else(|| anyhow!("No parity: {}", dir_path.display()))?;
+    ensure!(
+        idx < parity.sizes.len() as u64,
+        "Fragment {idx}: out of parity range"
+    );
+
+    let group = idx / parity.data_shards as u64;
+    let pos = (idx % parity.data_shards as u64) as usize;
+    let shards = group_shards(dir_path, name, parity, group);
+    let damaged = find_damaged(&shards).await;
+    if !damaged.contains(&pos) {
+        return Ok(None);
+    }
+
+    let n = shards.len() - parity.parity_shards;
+    let mut outputs = reconstruct_group(&shards, n, parity.parity_shards, &damaged).await?;
+    let i = damaged.iter().position(|&p| p == pos).unwrap();
+
+    Ok(Some(outputs.swap_remove(i)))
+}
+
+/// Regenerate damaged data and parity fragments in dir_path in place.
+/// Checksum files of PlainText fragments are regenerated from the parity info.
+///
+/// Return the number of repaired files.
+pub(super) async fn repair_fragments(
+    dir_path: &Path,
+    name: &str,
+    info: &CryptInfo,
+) -> Result<usize> {
+    let parity = info
+        .parity
+        .as_ref()
+        .ok_or_else(|| anyhow!("No parity: {}", dir_path.display()))?;
+    ensure!(
+        parity.sizes.len() as u64 == info.fragment_count(),
+        "Parity info unmatch (fragment count = {}, parity sizes = {})",
+        info.fragment_count(),
+        parity.sizes.len()
+    );
+
+    let mut repaired = 0;
+    for group in 0..group_count(parity) {
+        let shards = group_shards(dir_path, name, parity, group);
+        let damaged = find_damaged(&shards).await;
+        if damaged.is_empty() {
+            continue;
+        }
+
+        let n = shards.len() - parity.parity_shards;
+        let outputs = reconstruct_group(&shards, n, parity.parity_shards, &damaged).await?;
+        for (output, &pos) in outputs.into_iter().zip(&damaged) {
+            let path = &shards[pos].0;
+            util::write_atomic_async(path, output).await?;
+            warn!("Repaired: {}", path.display());
+            repaired += 1;
+        }
+    }
+
+    if info.crypt == CryptType::PlainText {
+        for (idx, md5str) in parity.data_md5.iter().enumerate() {
+            let path = dir_path.join(super::fragment_md5_name(name, idx as u64));
+            match tokio::fs::read_to_string(&path).await {
+                Ok(s) if s.get(..util::MD5STRLEN) == Some(md5str.as_str()) => continue,
+                _ => {}
+            }
+            util::write_atomic_async(&path, md5str.clone().into_bytes()).await?;
+            warn!("Repaired: {}", path.display());
+            repaired += 1;
+        }
+    }
+
+    Ok(repaired)
+}
+
+fn process_repair(dirpath: &Path, config: &Config, tags: &[String]) -> Result<()> {
+    for tag in tags {
+        ensure!(
+            config.repository.entries.contains_key(tag),
+            "Tag not found: {tag}"
+        );
+    }
+
+    let crypt_path = dirpath.join(super::DIRNAME_CRYPT);
+    let rt = Runtime::new()?;
+    let mut errors = 0;
+    for tag in config.repository.entries.keys() {
+        if !tags.is_empty() && !tags.contains(tag) {
+            continue;
+        }
+        let names = match restore::crypt_versions(&crypt_path.join(tag)) {
+            Ok(names) => names,
+            Err(err) => {
+                error!("{tag}: {err:#}");
+                errors += 1;
+                continue;
+            }
+        };
+
+        for name in names.iter() {
+            let result = (|| -> Result<usize> {
+                let src_dir_path = restore::find_crypt_dir(&crypt_path, tag, name)
+                    .ok_or_else(|| anyhow!("Crypt data not found: {tag}/{name}"))?;
+                let info = restore::read_crypt_info(&src_dir_path)?;
+                if info.parity.is_none() {
+                    info!("Skip (no parity): {tag}/{name}");
+                    return Ok(0);
+                }
+
+                rt.block_on(repair_fragments(&src_dir_path, name, &info))
+            })();
+            match result {
+                Ok(0) => info!("OK: {tag}/{name}"),
+                Ok(count) => info!("Repaired: {tag}/{name} ({count} files)"),
+                Err(err) => {
+                    error!("{tag}/{name}: {err:#}");
+                    errors += 1;
+                }
+            }
+        }
+    }
+    ensure!(errors == 0, "{errors} version(s) failed");
+
+    Ok(())
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str =
+        "Regenerate missing or damaged fragments in crypt/ from parity (all tags by default).";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, Some("[TAG...]")));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let tags = matches.free;
+
+    super::process_with_config_lock(basedir, |dirpath, config| {
+        process_repair(dirpath, &config, &tags)?;
+        Ok(None)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_repair() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let rt = Runtime::new()?;
+
+        // 7 fragments of various sizes: groups of 3, 3 and 1
+        let fragments: Vec<Vec<u8>> = (0..7u64)
+            .map(|idx| {
+                (0..(1000 + idx * 37))
+                    .map(|x| (x * (idx + 3)) as u8)
+                    .collect()
+            })
+            .collect();
+        for (idx, fragment) in fragments.iter().enumerate() {
+            std::fs::write(
+                dirpath.join(super::super::fragment_name("a.bin", idx as u64)),
+                fragment,
+            )?;
+            std::fs::write(
+                dirpath.join(super::super::fragment_md5_name("a.bin", idx as u64)),
+                util::md5_to_str(&Md5::digest(fragment)),
+            )?;
+        }
+        let parity = rt.block_on(write_parity(dirpath, "a.bin", 7, 3, 2))?;
+        assert_eq!(parity.data_md5.len(), 7);
+        assert_eq!(parity.parity_md5.len(), 6);
+        let info = CryptInfo {
+            crypt: CryptType::PlainText,
+            // 7 fragments (not used for parity)
+            total_size: 6 * 1300 + 1,
+            fragment_size: 1300.try_into()?,
+            aad: false,
+            header_version: 0,
+            compression: None,
+            parity: Some(parity),
+            chunks: None,
+        };
+
+        let path = |idx| dirpath.join(super::super::fragment_name("a.bin", idx));
+        let ppath = |idx| dirpath.join(super::super::fragment_parity_name("a.bin", idx));
+        assert_eq!(
+            rt.block_on(reconstruct_fragment(dirpath, "a.bin", &info, 1))?,
+            None
+        );
+
+        // group 0: a data and a parity lost,
+        // group 1: corrupted and a checksum lost, group 2: parity lost
+        let md5path = |idx| dirpath.join(super::super::fragment_md5_name("a.bin", idx));
+        std::fs::remove_file(path(0))?;
+        std::fs::remove_file(ppath(1))?;
+        std::fs::write(path(4), "broken")?;
+        std::fs::remove_file(md5path(5))?;
+        std::fs::remove_file(ppath(4))?;
+        let problems = restore::check_fragments(dirpath, "a.bin", &info);
+        assert!(problems.is_empty(), "{problems:?}");
+        assert_eq!(
+            rt.block_on(reconstruct_fragment(dirpath, "a.bin", &info, 0))?,
+            Some(fragments[0].clone())
+        );
+        assert_eq!(rt.block_on(repair_fragments(dirpath, "a.bin", &info))?, 5);
+        for (idx, fragment) in fragments.iter().enumerate() {
+            assert_eq!(std::fs::read(path(idx as u64))?, *fragment);
+            assert_eq!(
+                std::fs::read_to_string(md5path(idx as u64))?,
+                util::md5_to_str(&Md5::digest(fragment))
+            );
+        }
+        assert_eq!(rt.block_on(repair_fragments(dirpath, "a.bin", &info))?, 0);
+
+        // more than parity (a lost checksum counts)
+        std::fs::remove_file(md5path(3))?;
+        std::fs::remove_file(md5path(4))?;
+        std::fs::remove_file(ppath(2))?;
+        assert!(!restore::check_fragments(dirpath, "a.bin", &info).is_empty());
+        assert_eq!(rt.block_on(repair_fragments(dirpath, "a.bin", &info))?, 3);
+        for idx in 0..3 {
+            std::fs::remove_file(path(idx))?;
+        }
+        assert!(!restore::check_fragments(dirpath, "a.bin", &info).is_empty());
+        assert!(rt
+            .block_on(repair_fragments(dirpath, "a.bin", &info))
+            .is_err());
+
+        assert_eq!(parse_parity("10+2")?, (10, 2));
+        assert!(parse_parity("10").is_err());
+        assert!(parse_parity("0+2").is_err());
+        assert!(parse_parity("255+2").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1010 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use getopts::Options;
+use log::{debug, error, info, warn};
+use md5::{Digest, Md5};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+
+use super::{
+    Aes128GcmArgon2Param, Config, CryptInfo, CryptType, PassphraseKey, PassphraseSource,
+    RecipientKeys, WrappedKey,
+};
+use crate::cryptutil::{self, AesKey, AesKeyCheck, FragmentHeader, KdfId, X25519Key};
+use crate::util;
+
+/// Derive keys from the passphrase on demand.
+///
+/// Argon2 is slow by design, so keys are cached by parameters.
+/// The passphrase is asked at the first time it is needed.
+/// The private key file is also read on demand (public-key types).
+#[derive(Default)]
+pub(super) struct KeyCache {
+    source: PassphraseSource,
+    passphrase: Option<String>,
+    keys: BTreeMap<Aes128GcmArgon2Param, AesKey>,
+    /// Key-check values to validate the passphrase
+    checks: BTreeMap<Aes128GcmArgon2Param, AesKeyCheck>,
+    identity_path: Option<PathBuf>,
+    identity: Option<X25519Key>,
+}
+
+impl KeyCache {
+    pub(super) fn new(source: PassphraseSource) -> Self {
+        Self {
+            source,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn with_passphrase(passphrase: String) -> Self {
+        Self {
+            passphrase: Some(passphrase),
+            ..Default::default()
+        }
+    }
+
+    /// Use the key saved in secrets file (no passphrase needed)
+    /// and the key-check value in config.
+    pub(super) fn load(dirpath: &Path, config: &Config, source: PassphraseSource) -> Result<Self> {
+        let mut keys = Self::new(source);
+        if let Some(PassphraseKey {
+            key_check: Some(check),
+            argon2,
+            ..
+        }) = config.crypt.passphrase_key()
+        {
+            keys.checks.insert(argon2.clone(), *check);
+        }
+
+        if let Some(secrets) = super::read_secrets(dirpath)? {
+            match keys.checks.get(&secrets.argon2) {
+                Some(check) if cryptutil::aeskey_check(&secrets.key) != *check => {
+                    warn!("Ignore the saved key (key check failed)");
+                }
+                _ => {
+                    keys.keys.insert(secrets.argon2, secrets.key);
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    pub(super) fn get(&mut self, param: &Aes128GcmArgon2Param) -> Result<AesKey> {
+        if let Some(key) = self.keys.get(param) {
+            return Ok(*key);
+        }
+
+        let passphrase = match &self.passphrase {
+            Some(passphrase) => passphrase,
+            None => self
+                .passphrase
+                .insert(self.source.read("Passphrase", false)?),
+        };
+        info!("Derive key from passphrase");
+        let key = cryptutil::aeskey_from_password(
+            param.salt,
+            param.m_cost,
+            param.t_cost,
+            param.p_cost,
+            passphrase,
+        )?;
+        if let Some(check) = self.checks.get(param) {
+            if cryptutil::aeskey_check(&key) != *check {
+                // ask again next time (fd cannot be read twice)
+                if self.source.is_interactive() {
+                    self.passphrase = None;
+                }
+                bail!("Passphrase mismatch");
+            }
+        }
+        self.keys.insert(param.clone(), key);
+
+        Ok(key)
+    }
+
+    pub(super) fn set_identity(&mut self, path: Option<PathBuf>) {
+        self.identity_path = path;
+        self.identity = None;
+    }
+
+    /// The data key wrapped for the private key.
+    pub(super) fn recipient_data_key(&mut self, rk: &RecipientKeys) -> Result<AesKey> {
+        let secret = match self.identity {
+            Some(secret) => secret,
+            None => {
+                let path = self
+                    .identity_path
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Private key is needed (--identity)"))?;
+                *self.identity.insert(super::read_identity(path)?)
+            }
+        };
+
+        rk.open(&secret)
+    }
+
+    /// The key to en/decrypt data.
+    ///
+    /// The passphrase-derived key itself if not wrapped (old format).
+    pub(super) fn data_key(
+        &mut self,
+        param: &Aes128GcmArgon2Param,
+        wrapped: Option<&WrappedKey>,
+    ) -> Result<AesKey> {
+        let kek = self.get(param)?;
+        match wrapped {
+            Some(wrapped) => wrapped.unwrap(&kek),
+            None => Ok(kek),
+        }
+    }
+}
+
+pub(super) fn read_crypt_info(src_dir_path: &Path) -> Result<CryptInfo> {
+    let info_path = src_dir_path.join(super::CRYPT_INFO_NAME);
+    let toml = std::fs::read_to_string(&info_path)
+        .with_context(|| format!("Cannot read {}", info_path.display()))?;
+    let info: CryptInfo =
+        toml::from_str(&toml).with_context(|| format!("Invalid {}", info_path.display()))?;
+
+    Ok(info)
+}
+
+/// crypt/tag/name (or crypt/tag of the old layout, which holds one version only).
+///
+/// Return None if the version is not stored.
+pub(super) fn find_crypt_dir(crypt_path: &Path, tag: &str, name: &str) -> Option<PathBuf> {
+    let path = crypt_path.join(tag).join(name);
+    if path.join(super::CRYPT_INFO_NAME).is_file() {
+        return Some(path);
+    }
+
+    let path = crypt_path.join(tag);
+    let old_name = path
+        .join(super::CRYPT_INFO_NAME)
+        .is_file()
+        .then(|| super::recover::find_fragment_name(&path).ok().flatten())
+        .flatten();
+    (old_name.as_deref() == Some(name)).then_some(path)
+}
+
+/// Names of the versions stored in crypt/tag (both layouts).
+pub(super) fn crypt_versions(tag_path: &Path) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    if !tag_path.is_dir() {
+        return Ok(names);
+    }
+
+    if tag_path.join(super::CRYPT_INFO_NAME).is_file() {
+        let info = read_crypt_info(tag_path)?;
+        match super::recover::find_fragment_name(tag_path)? {
+            Some(name) => {
+                names.insert(name);
+            }
+            // empty file
+            None if info.fragment_count() == 0 && info.chunks.is_none() => {
+                names.insert(String::new());
+            }
+            None => bail!("No fragment found: {}", tag_path.display()),
+        }
+    }
+    for entry in tag_path
+        .read_dir()
+        .with_context(|| format!("Cannot read dir: {}", tag_path.display()))?
+    {
+        let entry = entry?;
+        if !entry.path().join(super::CRYPT_INFO_NAME).is_file() {
+            continue;
+        }
+        match entry.file_name().into_string() {
+            Ok(name) => {
+                names.insert(name);
+            }
+            Err(name) => warn!("Skip (invalid name): {}", name.to_string_lossy()),
+        }
+    }
+
+    Ok(names)
+}
+
+/// All crypt/tag/name (and crypt/tag of the old layout) with metadata.
+pub(super) fn crypt_dirs(crypt_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if !crypt_path.exists() {
+        return Ok(dirs);
+    }
+
+    for entry in crypt_path
+        .read_dir()
+        .with_context(|| format!("Cannot read dir: {}", crypt_path.display()))?
+    {
+        let entry = entry?;
+        let tag_path = entry.path();
+        if !tag_path.is_dir()
+            || entry.file_name() == super::DIRNAME_STAGING
+            || entry.file_name() == super::DIRNAME_CHUNKS
+        {
+            continue;
+        }
+
+        if tag_path.join(super::CRYPT_INFO_NAME).is_file() {
+            dirs.push(tag_path.clone());
+        }
+        for entry in tag_path
+            .read_dir()
+            .with_context(|| format!("Cannot read dir: {}", tag_path.display()))?
+        {
+            let path = entry?.path();
+            if path.join(super::CRYPT_INFO_NAME).is_file() {
+                dirs.push(path);
+            }
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Check that "name.000000", "name.000001", ... in src_dir_path agree with info.
+///
+/// Return a list of problems (empty if OK).
+/// Problems recoverable by [CryptInfo::parity] are only warned.
+pub(super) fn check_fragments(src_dir_path: &Path, name: &str, info: &CryptInfo) -> Vec<String> {
+    if info.chunks.is_some() {
+        return super::chunk::check_chunks(src_dir_path, name, info);
+    }
+
+    let mut problems = Vec::new();
+    // parity group -> (damaged files, problems)
+    let mut groups: BTreeMap<u64, (usize, Vec<String>)> = BTreeMap::new();
+
+    // header and tag
+    let overhead = match info.crypt.cipher() {
+        None => 0,
+        Some(cipher) => match FragmentHeader::size_of(info.header_version, cipher) {
+            Some(size) => (size + cipher.tag_size()) as u64,
+            None => {
+                problems.push(format!(
+                    "Unsupported header version: {}",
+                    info.header_version
+                ));
+                return problems;
+            }
+        },
+    };
+
+    let count = info.fragment_count();
+    let parity = info.parity.as_ref();
+    if let Some(parity) = parity {
+        if parity.sizes.len() as u64 != count {
+            problems.push(format!(
+                "Parity info unmatch (fragment count = {count}, parity sizes = {})",
+                parity.sizes.len()
+            ));
+            return problems;
+        }
+    }
+
+    let mut rest = info.total_size;
+    for idx in 0..count {
+        let plain_size = rest.min(info.fragment_size.get());
+        rest -= plain_size;
+
+        // the stored size is recorded with parity
+        let expected = parity.map_or(overhead + plain_size, |parity| parity.sizes[idx as usize]);
+        let path = src_dir_path.join(super::fragment_name(name, idx));
+        let mut fragment_problems = Vec::new();
+        match path.metadata() {
+            // compressed size is unknown, at least header and tag
+            Ok(meta) if info.compression.is_some() && parity.is_none() => {
+                if meta.len() <= overhead {
+                    fragment_problems.push(format!(
+                        "Fragment {idx}: too short ({} bytes): {}",
+                        meta.len(),
+                        path.display()
+                    ));
+                }
+            }
+            Ok(meta) => {
+                if meta.len() != expected {
+                    fragment_problems.push(format!(
+                        "Fragment {idx}: size unmatch (expected {expected}, actual {}): {}",
+                        meta.len(),
+                        path.display()
+                    ));
+                }
+            }
+            Err(err) => {
+                fragment_problems.push(format!("Fragment {idx}: {err}: {}", path.display()));
+            }
+        }
+        // regenerated from the parity info by repair
+        if info.crypt == CryptType::PlainText {
+            let md5_path = src_dir_path.join(super::fragment_md5_name(name, idx));
+            if !md5_path.is_file() {
+                fragment_problems.push(format!(
+                    "Fragment {idx}: checksum not found: {}",
+                    md5_path.display()
+                ));
+            }
+        }
+        match parity {
+            Some(parity) if !fragment_problems.is_empty() => {
+                let group = groups.entry(idx / parity.data_shards as u64).or_default();
+                group.0 += 1;
+                group.1.extend(fragment_problems);
+            }
+            _ => problems.extend(fragment_problems),
+        }
+    }
+
+    // fragments after the last one
+    let path = src_dir_path.join(super::fragment_name(name, count));
+    if path.exists() {
+        problems.push(format!(
+            "Fragment {count}: unexpected (fragment count = {count}): {}",
+            path.display()
+        ));
+    }
+
+    if let Some(parity) = parity {
+        for (pidx, expected) in super::repair::parity_sizes(parity).into_iter().enumerate() {
+            let path = src_dir_path.join(super::fragment_parity_name(name, pidx as u64));
+            let problem = match path.metadata() {
+                Ok(meta) if meta.len() == expected => continue,
+                Ok(meta) => format!(
+                    "Parity {pidx}: size unmatch (expected {expected}, actual {}): {}",
+                    meta.len(),
+                    path.display()
+                ),
+                Err(err) => format!("Parity {pidx}: {err}: {}", path.display()),
+            };
+            let group = groups
+                .entry((pidx / parity.parity_shards) as u64)
+                .or_default();
+            group.0 += 1;
+            group.1.push(problem);
+        }
+        for (damaged, group_problems) in groups.into_values() {
+            if damaged <= parity.parity_shards {
+                for problem in group_problems {
+                    warn!("{problem} (recoverable by parity)");
+                }
+            } else {
+                problems.extend(group_problems);
+            }
+        }
+    }
+
+    problems
+}
+
+/// Read "name.NNNNNN" in src_dir_path and return the plain data.
+///
+/// Checksum (PlainText) or authentication tag (AES/XChaCha20) is verified.
+/// tag and name are authenticated if [CryptInfo::aad].
+/// Decompressed if [CryptInfo::compression].
+/// The file contents are replaced with repaired if Some.
+async fn read_fragment(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    idx: u64,
+    info: &CryptInfo,
+    keys: &mut KeyCache,
+    repaired: Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let src_path = src_dir_path.join(super::fragment_name(name, idx));
+    debug!("From: {}", src_path.display());
+    let buf = match repaired {
+        Some(buf) => buf,
+        None => tokio::fs::read(&src_path)
+            .await
+            .with_context(|| format!("Cannot read {}", src_path.display()))?,
+    };
+
+    let plain = match info.crypt {
+        CryptType::PlainText => {
+            let md5_path = src_dir_path.join(super::fragment_md5_name(name, idx));
+            let mut md5str = match tokio::fs::read_to_string(&md5_path).await {
+                Ok(md5str) => md5str,
+                // the same MD5 is recorded in the parity info
+                Err(err)
+                    if err.kind() == ErrorKind::NotFound
+                        && info
+                            .parity
+                            .as_ref()
+                            .is_some_and(|parity| parity.data_md5.len() as u64 > idx) =>
+                {
+                    warn!("Fragment {idx}: checksum not found, use parity info");
+                    info.parity.as_ref().unwrap().data_md5[idx as usize].clone()
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Cannot read {}", md5_path.display()))
+                }
+            };
+            md5str.truncate(util::MD5STRLEN);
+            let md5 = util::str_to_md5(&md5str)
+                .with_context(|| format!("Failed to convert to MD5 {}", md5_path.display()))?;
+            ensure!(
+                *Md5::digest(&buf) == md5,
+                "Fragment {idx}: MD5 unmatch: {}",
+                src_path.display()
+            );
+
+            buf
+        }
+        CryptType::Aes128GcmArgon2(_)
+        | CryptType::XChaCha20Poly1305Argon2(_)
+        | CryptType::X25519(_) => {
+            let header = FragmentHeader::parse(&buf).with_context(|| {
+                format!("Fragment {idx}: invalid header: {}", src_path.display())
+            })?;
+            ensure!(
+                header.version == info.header_version,
+                "Fragment {idx}: header version unmatch (expected {}, actual {}): {}",
+                info.header_version,
+                header.version,
+                src_path.display()
+            );
+            ensure!(
+                Some(header.cipher) == info.crypt.cipher(),
+                "Fragment {idx}: cipher unmatch (actual {:?}): {}",
+                header.cipher,
+                src_path.display()
+            );
+            if let Some(index) = header.index {
+                ensure!(
+                    index == idx,
+                    "Fragment {idx}: index unmatch (actual {index}): {}",
+                    src_path.display()
+                );
+            }
+            let key = match &info.crypt {
+                CryptType::X25519(rk) => {
+                    ensure!(
+                        header.kdf == KdfId::X25519Hkdf,
+                        "Fragment {idx}: KDF unmatch (actual {:?}): {}",
+                        header.kdf,
+                        src_path.display()
+                    );
+                    keys.recipient_data_key(rk)?
+                }
+                crypt => {
+                    let pk = crypt.passphrase_key().unwrap();
+                    ensure!(
+                        header.kdf == KdfId::Argon2id,
+                        "Fragment {idx}: KDF unmatch (actual {:?}): {}",
+                        header.kdf,
+                        src_path.display()
+                    );
+                    // Argon2 param in the header is old if the data key was re-wrapped
+                    match &pk.wrapped_key {
+                        Some(wrapped) => keys.data_key(&pk.argon2, Some(wrapped))?,
+                        None => keys.data_key(&Aes128GcmArgon2Param::from_header(&header), None)?,
+                    }
+                }
+            };
+            let aad = if info.aad {
+                super::fragment_aad(tag, name, idx, info.fragment_count())
+            } else {
+                Vec::new()
+            };
+            let ciphertext = &buf[header.header_len()..];
+            header
+                .cipher
+                .decrypt(&key, &header.nonce, ciphertext, &aad)
+                .with_context(|| {
+                    format!("Fragment {idx}: decryption failed: {}", src_path.display())
+                })?
+        }
+    };
+
+    let plain = match info.compression {
+        Some(compression) => compression
+            .decompress(&plain, info.fragment_size.get() as usize)
+            .with_context(|| {
+                format!(
+                    "Fragment {idx}: decompression failed: {}",
+                    src_path.display()
+                )
+            })?,
+        None => plain,
+    };
+
+    Ok(plain)
+}
+
+/// Decrypt "name.000000", "name.000001", ... in src_dir_path and write to dst_path.
+/// If dst_path is None, plain data is discarded (verify only).
+/// dst_path is overwritten and fsynced (see [decrypt_to_file]).
+///
+/// Return MD5 of the restored file.
+pub(super) async fn decrypt_fragments(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    info: &CryptInfo,
+    keys: &mut KeyCache,
+    dst_path: Option<&Path>,
+) -> Result<[u8; util::MD5LEN]> {
+    let mut fout = if let Some(dst_path) = dst_path {
+        let file = tokio::fs::File::create(dst_path)
+            .await
+            .with_context(|| format!("Cannot create {}", dst_path.display()))?;
+        Some(file)
+    } else {
+        None
+    };
+    let md5 = if info.chunks.is_some() {
+        super::chunk::decrypt_chunks(src_dir_path, tag, name, info, keys, fout.as_mut()).await?
+    } else {
+        decrypt_fragment_files(src_dir_path, tag, name, info, keys, fout.as_mut()).await?
+    };
+    if let Some(fout) = &mut fout {
+        fout.sync_all().await?;
+    }
+
+    Ok(md5)
+}
+
+async fn decrypt_fragment_files(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    info: &CryptInfo,
+    keys: &mut KeyCache,
+    mut fout: Option<&mut tokio::fs::File>,
+) -> Result<[u8; util::MD5LEN]> {
+    let mut hasher = Md5::new();
+    let mut rest = info.total_size;
+    for idx in 0..info.fragment_count() {
+        let plain = match read_fragment(src_dir_path, tag, name, idx, info, keys, None).await {
+            // a wrong key is not fixed by parity (the file is intact)
+            Err(err) if info.parity.is_some() => {
+                match super::repair::reconstruct_fragment(src_dir_path, name, info, idx).await? {
+                    Some(buf) => {
+                        warn!("Fragment {idx}: reconstructed from parity ({err:#})");
+                        read_fragment(src_dir_path, tag, name, idx, info, keys, Some(buf)).await?
+                    }
+                    None => return Err(err),
+                }
+            }
+            result => result?,
+        };
+
+        let expected = rest.min(info.fragment_size.get());
+        ensure!(
+            plain.len() as u64 == expected,
+            "Fragment {idx}: size unmatch (expected {expected}, actual {})",
+            plain.len(),
+        );
+        rest -= expected;
+
+        hasher.update(&plain);
+        if let Some(fout) = &mut fout {
+            fout.write_all(&plain).await?;
+        }
+    }
+    if let Some(fout) = &mut fout {
+        fout.flush().await?;
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Decrypt into "dst_path.tmp", check MD5 by verify and rename to dst_path.
+///
+/// dst_path never has partial data; the tmp file is removed if failed.
+pub(super) fn decrypt_to_file(
+    src_dir_path: &Path,
+    tag: &str,
+    name: &str,
+    info: &CryptInfo,
+    keys: &mut KeyCache,
+    dst_path: &Path,
+    verify: impl FnOnce(&[u8; util::MD5LEN]) -> Result<()>,
+) -> Result<[u8; util::MD5LEN]> {
+    ensure!(
+        dst_path.symlink_metadata().is_err(),
+        "Already exists: {}",
+        dst_path.display()
+    );
+    let tmp_path = util::tmp_path(dst_path);
+
+    let rt = Runtime::new()?;
+    let res = rt
+        .block_on(decrypt_fragments(
+            src_dir_path,
+            tag,
+            name,
+            info,
+            keys,
+            Some(&tmp_path),
+        ))
+        .and_then(|md5| {
+            verify(&md5)?;
+            std::fs::rename(&tmp_path, dst_path)
+                .with_context(|| format!("Rename failed: {}", tmp_path.display()))?;
+            if let Some(parent) = dst_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                util::sync_dir(parent)?;
+            }
+            Ok(md5)
+        });
+    drop(rt);
+
+    if res.is_err() {
+        match std::fs::remove_file(&tmp_path) {
+            Ok(()) => info!("Delete OK: {}", tmp_path.display()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => warn!("Delete failed: {}: {err}", tmp_path.display()),
+        }
+    }
+
+    res
+}
+
+fn process_restore(
+    dirpath: &Path,
+    config: &Config,
+    tag: &str,
+    version: Option<&str>,
+    out_dir_path: &Path,
+    source: PassphraseSource,
+    identity: Option<PathBuf>,
+) -> Result<()> {
+    let ents = config
+        .repository
+        .entries
+        .get(tag)
+        .ok_or_else(|| anyhow!("Tag not found: {tag}"))?;
+    let rf = if let Some(version) = version {
+        // match with file name or date part
+        ents.iter()
+            .map(|rf| &rf.0)
+            .find(|rf| {
+                rf.name == version
+                    || super::split_filename(&rf.name).is_ok_and(|(_, date, _)| date == version)
+            })
+            .ok_or_else(|| anyhow!("Version not found: {tag} {version}"))?
+    } else {
+        &ents
+            .first()
+            .ok_or_else(|| anyhow!("No file in tag: {tag}"))?
+            .0
+    };
+    ensure!(rf.crypt, "Not encrypted yet: {}", rf.name);
+
+    let src_dir_path = find_crypt_dir(&dirpath.join(super::DIRNAME_CRYPT), tag, &rf.name)
+        .ok_or_else(|| anyhow!("Crypt data not found: {tag}/{}", rf.name))?;
+    let info = read_crypt_info(&src_dir_path)?;
+    info!(
+        "Restore: {} ({} files, {} bytes)",
+        rf.name,
+        info.fragment_count(),
+        info.total_size
+    );
+
+    let problems = check_fragments(&src_dir_path, &rf.name, &info);
+    for problem in problems.iter() {
+        error!("{problem}");
+    }
+    ensure!(problems.is_empty(), "{} problem(s) found", problems.len());
+
+    let mut keys = KeyCache::load(dirpath, config, source)?;
+    keys.set_identity(identity);
+
+    std::fs::create_dir_all(out_dir_path)
+        .with_context(|| format!("Mkdir failed: {}", out_dir_path.display()))?;
+    let dst_path = out_dir_path.join(&rf.name);
+
+    // compare with the record in repo/ before renaming
+    let repo_md5_path = dirpath
+        .join(super::DIRNAME_REPO)
+        .join(tag)
+        .join(&rf.md5name);
+    let md5 = decrypt_to_file(
+        &src_dir_path,
+        tag,
+        &rf.name,
+        &info,
+        &mut keys,
+        &dst_path,
+        |md5| {
+            let md5str = util::md5_to_str(md5);
+            match std::fs::read_to_string(&repo_md5_path) {
+                Ok(mut repo_md5str) => {
+                    repo_md5str.truncate(util::MD5STRLEN);
+                    ensure!(
+                        repo_md5str == md5str,
+                        "MD5 unmatch: {} (repo {repo_md5str}, restored {md5str})",
+                        dst_path.display()
+                    );
+                    info!("MD5 verify OK: {}", dst_path.display());
+                }
+                Err(err) => {
+                    warn!("Cannot read {}: {err}", repo_md5_path.display());
+                    warn!("MD5 verify skipped: {}", dst_path.display());
+                }
+            }
+            Ok(())
+        },
+    )?;
+    info!("Write OK: {}", dst_path.display());
+
+    let dst_md5_path = out_dir_path.join(&rf.md5name);
+    std::fs::write(&dst_md5_path, util::md5_to_str(&md5))
+        .with_context(|| format!("Cannot write {}", dst_md5_path.display()))?;
+    info!("Write OK: {}", dst_md5_path.display());
+
+    Ok(())
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str = "Decrypt and restore a file from crypt/ (the latest version by default).";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optopt("o", "output", "Output directory (default=.)", "<DIR>");
+    PassphraseSource::add_options(&mut opts);
+    super::add_identity_option(&mut opts);
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!(
+            "{}",
+            util::create_help(cmd, DESC, &opts, Some("TAG [VERSION]"))
+        );
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+
+    ensure!(!matches.free.is_empty(), "TAG is required");
+    ensure!(matches.free.len() <= 2, "Too much arguments");
+    let tag = matches.free[0].as_str();
+    let version = matches.free.get(1).map(|s| s.as_str());
+    let out_dir = PathBuf::from(matches.opt_str("o").unwrap_or(".".to_string()));
+    let source = PassphraseSource::from_matches(&matches)?;
+    let identity = matches.opt_str("i").map(PathBuf::from);
+
+    // config.toml is not written, other commands can run while decrypting
+    let config = super::read_config_with_lock(basedir)?;
+    process_restore(basedir, &config, tag, version, &out_dir, source, identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_decrypt_fragments() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+
+        let password = "password";
+        let (salt, m_cost, t_cost, p_cost, key) = cryptutil::aeskey_new_from_password(password);
+        let argon2 = Aes128GcmArgon2Param {
+            salt,
+            m_cost,
+            t_cost,
+            p_cost,
+        };
+
+        // 2.5 fragments
+        let plain: Vec<u8> = (0..2560u32).map(|x| x as u8).collect();
+        let info = CryptInfo {
+            crypt: CryptType::Aes128GcmArgon2(PassphraseKey {
+                key_check: None,
+                argon2: argon2.clone(),
+                wrapped_key: None,
+            }),
+            total_size: plain.len() as u64,
+            fragment_size: NonZeroU64::new(1024).unwrap(),
+            aad: true,
+            header_version: cryptutil::FRAGMENT_VERSION,
+            compression: None,
+            parity: None,
+            chunks: None,
+        };
+        for (idx, chunk) in plain.chunks(1024).enumerate() {
+            let aad = super::super::fragment_aad("a", "a.bin", idx as u64, 3);
+            let (nonce, encbuf) = cryptutil::encrypt_aes256gcm(&key, chunk, &aad)?;
+            let mut buf = argon2
+                .to_header(cryptutil::CipherId::Aes256Gcm, idx as u64, nonce.to_vec())
+                .to_bytes();
+            buf.extend_from_slice(&encbuf);
+            std::fs::write(
+                dirpath.join(super::super::fragment_name("a.bin", idx as u64)),
+                buf,
+            )?;
+        }
+
+        assert!(check_fragments(dirpath, "a.bin", &info).is_empty());
+
+        let rt = Runtime::new()?;
+        let dst_path = dirpath.join("out.bin");
+        let mut keys = KeyCache::with_passphrase(password.to_string());
+        let md5 = rt.block_on(decrypt_fragments(
+            dirpath,
+            "a",
+            "a.bin",
+            &info,
+            &mut keys,
+            Some(&dst_path),
+        ))?;
+        assert_eq!(std::fs::read(&dst_path)?, plain);
+        assert_eq!(md5, *Md5::digest(&plain));
+
+        // wrong passphrase
+        let dst_path = dirpath.join("out2.bin");
+        let mut keys = KeyCache::with_passphrase("wrong".to_string());
+        let res = rt.block_on(decrypt_fragments(
+            dirpath,
+            "a",
+            "a.bin",
+            &info,
+            &mut keys,
+            Some(&dst_path),
+        ));
+        assert!(res.is_err());
+
+        // another tag, truncated, or without AAD
+        let mut keys = KeyCache::with_passphrase(password.to_string());
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "b", "a.bin", &info, &mut keys, None,
+        ));
+        assert!(res.is_err());
+        let mut truncated = info.clone();
+        truncated.total_size = 2048;
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &truncated, &mut keys, None,
+        ));
+        assert!(res.is_err());
+        let mut old = info.clone();
+        old.aad = false;
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &old, &mut keys, None,
+        ));
+        assert!(res.is_err());
+
+        // the same data key wrapped by another passphrase
+        let password2 = "password2";
+        let (salt, m_cost, t_cost, p_cost, kek2) = cryptutil::aeskey_new_from_password(password2);
+        let mut info2 = info.clone();
+        info2.crypt = CryptType::Aes128GcmArgon2(PassphraseKey {
+            key_check: None,
+            argon2: Aes128GcmArgon2Param {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            wrapped_key: Some(WrappedKey::wrap(&kek2, &key)?),
+        });
+        let mut keys = KeyCache::with_passphrase(password2.to_string());
+        let md5 = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &info2, &mut keys, None,
+        ))?;
+        assert_eq!(md5, *Md5::digest(&plain));
+        let mut keys = KeyCache::with_passphrase(password.to_string());
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &info2, &mut keys, None,
+        ));
+        assert!(res.is_err());
+
+        // the same fragments declared as another cipher
+        let mut info3 = info.clone();
+        info3.crypt =
+            CryptType::XChaCha20Poly1305Argon2(info.crypt.passphrase_key().unwrap().clone());
+        let mut keys = KeyCache::with_passphrase(password.to_string());
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &info3, &mut keys, None,
+        ));
+        assert!(res.is_err());
+
+        // output via tmp: nothing is left if failed
+        let out_dir = dirpath.join("out");
+        std::fs::create_dir(&out_dir)?;
+        let dst_path = out_dir.join("a.bin");
+        let res = decrypt_to_file(dirpath, "a", "a.bin", &info3, &mut keys, &dst_path, |_| {
+            Ok(())
+        });
+        assert!(res.is_err());
+        let res = decrypt_to_file(dirpath, "a", "a.bin", &info, &mut keys, &dst_path, |_| {
+            bail!("MD5 unmatch")
+        });
+        assert!(res.is_err());
+        assert_eq!(out_dir.read_dir()?.count(), 0);
+        let md5 = decrypt_to_file(dirpath, "a", "a.bin", &info, &mut keys, &dst_path, |md5| {
+            ensure!(*md5 == *Md5::digest(&plain));
+            Ok(())
+        })?;
+        assert_eq!(md5, *Md5::digest(&plain));
+        assert_eq!(std::fs::read(&dst_path)?, plain);
+        assert_eq!(out_dir.read_dir()?.count(), 1);
+        // not overwritten
+        let res = decrypt_to_file(dirpath, "a", "a.bin", &info, &mut keys, &dst_path, |_| {
+            Ok(())
+        });
+        assert!(format!("{:#}", res.unwrap_err()).contains("Already exists"));
+
+        // swapped
+        let path0 = dirpath.join(super::super::fragment_name("a.bin", 0));
+        let path1 = dirpath.join(super::super::fragment_name("a.bin", 1));
+        let tmp_path = dirpath.join("tmp");
+        std::fs::rename(&path0, &tmp_path)?;
+        std::fs::rename(&path1, &path0)?;
+        std::fs::rename(&tmp_path, &path1)?;
+        assert!(check_fragments(dirpath, "a.bin", &info).is_empty());
+        let res = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &info, &mut keys, None,
+        ));
+        assert!(res.is_err());
+
+        // lost fragment
+        std::fs::remove_file(dirpath.join(super::super::fragment_name("a.bin", 1)))?;
+        let problems = check_fragments(dirpath, "a.bin", &info);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("Fragment 1:"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plain_parity_checksum_lost() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let rt = Runtime::new()?;
+
+        // 2.5 fragments with 2+1 parity
+        let plain: Vec<u8> = (0..2560u32).map(|x| (x * 7) as u8).collect();
+        for (idx, chunk) in plain.chunks(1024).enumerate() {
+            let idx = idx as u64;
+            std::fs::write(
+                dirpath.join(super::super::fragment_name("a.bin", idx)),
+                chunk,
+            )?;
+            std::fs::write(
+                dirpath.join(super::super::fragment_md5_name("a.bin", idx)),
+                util::md5_to_str(&Md5::digest(chunk)),
+            )?;
+        }
+        let parity = rt.block_on(super::super::repair::write_parity(
+            dirpath, "a.bin", 3, 2, 1,
+        ))?;
+        let info = CryptInfo {
+            crypt: CryptType::PlainText,
+            total_size: plain.len() as u64,
+            fragment_size: NonZeroU64::new(1024).unwrap(),
+            aad: false,
+            header_version: 0,
+            compression: None,
+            parity: Some(parity),
+            chunks: None,
+        };
+
+        // recoverable by parity, and restorable as is
+        std::fs::remove_file(dirpath.join(super::super::fragment_md5_name("a.bin", 1)))?;
+        let problems = check_fragments(dirpath, "a.bin", &info);
+        assert!(problems.is_empty(), "{problems:?}");
+        let mut keys = KeyCache::new(Default::default());
+        let md5 = rt.block_on(decrypt_fragments(
+            dirpath, "a", "a.bin", &info, &mut keys, None,
+        ))?;
+        assert_eq!(md5, *Md5::digest(&plain));
+
+        // and a data fragment in the same group
+        std::fs::remove_file(dirpath.join(super::super::fragment_name("a.bin", 0)))?;
+        assert_eq!(check_fragments(dirpath, "a.bin", &info).len(), 2);
+
+        Ok(())
+    }
+}
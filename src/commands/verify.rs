@@ -0,0 +1,508 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use getopts::Options;
+use log::{debug, error, info, warn};
+use tokio::runtime::Runtime;
+
+use super::restore::{self, KeyCache};
+use super::{Config, PassphraseSource, RepositoryFile};
+use crate::util::{self, TaskLimit};
+
+#[derive(Default)]
+struct TagReport {
+    ok: u32,
+    errors: Vec<anyhow::Error>,
+}
+
+/// Re-hash repo/tag/name and compare with repo/tag/md5name.
+async fn verify_file(repo_path: Arc<PathBuf>, tag: &str, rf: &RepositoryFile) -> Result<()> {
+    let path = repo_path.join(tag).join(&rf.name);
+    let md5_path = repo_path.join(tag).join(&rf.md5name);
+    ensure!(path.is_file(), "File not found: {}", path.display());
+
+    let mut md5str = tokio::fs::read_to_string(&md5_path)
+        .await
+        .with_context(|| format!("Cannot read {}", md5_path.display()))?;
+    md5str.truncate(util::MD5STRLEN);
+    let expected = util::str_to_md5(&md5str)
+        .with_context(|| format!("Failed to convert to MD5 {}", md5_path.display()))?;
+
+    debug!("Verify: {}", path.display());
+    let md5 = util::md5_file(&path)
+        .await
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+    ensure!(
+        md5 == expected,
+        "MD5 unmatch: {} (expected {md5str}, actual {})",
+        path.display(),
+        util::md5_to_str(&md5)
+    );
+    info!("MD5 verify OK: {}", path.display());
+
+    Ok(())
+}
+
+async fn verify_files(
+    repo_path: PathBuf,
+    files: Vec<(String, RepositoryFile)>,
+    limit: Arc<TaskLimit>,
+) -> BTreeMap<String, TagReport> {
+    info!("{} files to be verified", files.len());
+    let repo_path = Arc::new(repo_path);
+    let handles: Vec<_> = files
+        .into_iter()
+        .map(|(tag, rf)| {
+            let repo_path = Arc::clone(&repo_path);
+            let limit = Arc::clone(&limit);
+            // create a task (jobs files are read at the same time)
+            tokio::spawn(async move {
+                let res = match limit.acquire(0).await {
+                    Ok(_permit) => verify_file(repo_path, &tag, &rf).await,
+                    Err(err) => Err(err),
+                };
+                (tag, res)
+            })
+        })
+        .collect();
+
+    let mut reports: BTreeMap<String, TagReport> = BTreeMap::new();
+    for h in handles {
+        // JoinError happens only if cancel or panic
+        let (tag, res) = h.await.unwrap();
+        let report = reports.entry(tag).or_default();
+        match res {
+            Ok(()) => report.ok += 1,
+            Err(err) => report.errors.push(err),
+        }
+    }
+
+    reports
+}
+
+/// Decrypt crypt/tag/name in memory (plain data is discarded).
+async fn verify_crypt_dir(
+    dirpath: &Path,
+    tag: &str,
+    name: &str,
+    ents: &BTreeSet<Reverse<RepositoryFile>>,
+    keys: &mut KeyCache,
+    check_md5: bool,
+) -> Result<()> {
+    let src_dir_path = restore::find_crypt_dir(&dirpath.join(super::DIRNAME_CRYPT), tag, name)
+        .ok_or_else(|| anyhow!("Crypt data not found: {tag}/{name}"))?;
+
+    let info = restore::read_crypt_info(&src_dir_path)?;
+    info!(
+        "Verify: {} ({} files, {} bytes)",
+        src_dir_path.display(),
+        info.fragment_count(),
+        info.total_size
+    );
+
+    let problems = restore::check_fragments(&src_dir_path, name, &info);
+    ensure!(
+        problems.is_empty(),
+        "{} problem(s) found: {}",
+        problems.len(),
+        problems.join(", ")
+    );
+
+    // each fragment size and the total size are checked
+    let md5 = restore::decrypt_fragments(&src_dir_path, tag, name, &info, keys, None).await?;
+    info!("Decrypt OK: {}", src_dir_path.display());
+
+    if check_md5 {
+        let rf = ents
+            .iter()
+            .map(|rf| &rf.0)
+            .find(|rf| rf.name == name)
+            .ok_or_else(|| anyhow!("Not found in repository: {name}"))?;
+        let md5_path = dirpath
+            .join(super::DIRNAME_REPO)
+            .join(tag)
+            .join(&rf.md5name);
+        let mut md5str = tokio::fs::read_to_string(&md5_path)
+            .await
+            .with_context(|| format!("Cannot read {}", md5_path.display()))?;
+        md5str.truncate(util::MD5STRLEN);
+        ensure!(
+            md5str == util::md5_to_str(&md5),
+            "MD5 unmatch: {} (repo {md5str}, decrypted {})",
+            src_dir_path.display(),
+            util::md5_to_str(&md5)
+        );
+        info!("MD5 verify OK: {}", src_dir_path.display());
+    }
+
+    Ok(())
+}
+
+async fn verify_crypt_dirs(
+    dirpath: &Path,
+    config: &Config,
+    tags: &[&String],
+    check_md5: bool,
+    source: PassphraseSource,
+    identity: Option<PathBuf>,
+) -> BTreeMap<String, TagReport> {
+    let mut keys = match KeyCache::load(dirpath, config, source.clone()) {
+        Ok(keys) => keys,
+        Err(err) => {
+            warn!("{:#}", err);
+            KeyCache::new(source)
+        }
+    };
+    keys.set_identity(identity);
+
+    // sequential (the key is derived from the passphrase only once)
+    let mut reports: BTreeMap<String, TagReport> = BTreeMap::new();
+    for &tag in tags {
+        let ents = &config.repository.entries[tag];
+        let report = reports.entry(tag.clone()).or_default();
+        let tag_path = dirpath.join(super::DIRNAME_CRYPT).join(tag);
+        let names = match restore::crypt_versions(&tag_path) {
+            Ok(names) => names,
+            Err(err) => {
+                report.errors.push(err);
+                continue;
+            }
+        };
+        if names.is_empty() {
+            info!("[{tag}] No crypt data");
+        }
+        for name in names.iter() {
+            match verify_crypt_dir(dirpath, tag, name, ents, &mut keys, check_md5).await {
+                Ok(()) => report.ok += 1,
+                Err(err) => report.errors.push(err),
+            }
+        }
+
+        // encrypted versions whose crypt data is lost
+        // (older ones than the version in the old layout were overwritten)
+        let old_name = tag_path
+            .join(super::CRYPT_INFO_NAME)
+            .is_file()
+            .then(|| super::recover::find_fragment_name(&tag_path).ok().flatten())
+            .flatten();
+        for rf in ents.iter().map(|rf| &rf.0) {
+            if !rf.crypt || names.contains(&rf.name) {
+                continue;
+            }
+            if old_name
+                .as_ref()
+                .is_some_and(|old_name| rf.name < *old_name)
+            {
+                warn!("[{tag}] Crypt data not found (old layout): {}", rf.name);
+            } else {
+                report
+                    .errors
+                    .push(anyhow!("Crypt data not found: {tag}/{}", rf.name));
+            }
+        }
+    }
+
+    reports
+}
+
+/// Options of the command line.
+#[derive(Default)]
+struct VerifyParam {
+    /// crypt/ instead of repo/
+    crypt: bool,
+    /// Compare decrypted data with repo/ (crypt only)
+    check_md5: bool,
+    source: PassphraseSource,
+    identity: Option<PathBuf>,
+    /// Files in repo/ read at the same time
+    jobs: usize,
+}
+
+fn process_verify(
+    dirpath: &Path,
+    config: &Config,
+    tags: &[String],
+    param: VerifyParam,
+) -> Result<()> {
+    let repo_path = dirpath.join(super::DIRNAME_REPO);
+
+    for tag in tags {
+        ensure!(
+            config.repository.entries.contains_key(tag),
+            "Tag not found: {tag}"
+        );
+    }
+    let target_tags: Vec<_> = config
+        .repository
+        .entries
+        .keys()
+        .filter(|&tag| tags.is_empty() || tags.contains(tag))
+        .collect();
+
+    let rt = Runtime::new()?;
+    let reports = if param.crypt {
+        rt.block_on(verify_crypt_dirs(
+            dirpath,
+            config,
+            &target_tags,
+            param.check_md5,
+            param.source,
+            param.identity,
+        ))
+    } else {
+        let files: Vec<_> = target_tags
+            .iter()
+            .flat_map(|&tag| {
+                config.repository.entries[tag]
+                    .iter()
+                    .map(|rf| (tag.clone(), rf.0.clone()))
+            })
+            .collect();
+        let limit = Arc::new(TaskLimit::new(param.jobs, u64::MAX));
+        rt.block_on(verify_files(repo_path, files, limit))
+    };
+    drop(rt);
+
+    let mut failed = 0;
+    for (tag, report) in reports.iter() {
+        for err in report.errors.iter() {
+            error!("[{tag}] {:#}", err);
+        }
+        info!("[{tag}] OK: {}, NG: {}", report.ok, report.errors.len());
+        failed += report.errors.len();
+    }
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(anyhow!("{failed} file(s) failed to verify"))
+    }
+}
+
+pub fn entry(basedir: &Path, cmd: &str, args: &[String]) -> Result<()> {
+    const DESC: &str =
+        "Verify files in repo/ with MD5 or crypt/ by decryption (all tags by default).";
+    const USAGE_HINT: &str = "--help or -h to show usage";
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help");
+    opts.optflag(
+        "c",
+        "crypt",
+        "Verify crypt/ instead of repo/ (decrypt in memory)",
+    );
+    opts.optflag(
+        "m",
+        "md5",
+        "Compare decrypted data with MD5 in repo/ (with --crypt)",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "Files verified at the same time (default: the number of CPUs)",
+        "N",
+    );
+    PassphraseSource::add_options(&mut opts);
+    super::add_identity_option(&mut opts);
+
+    if util::find_option(&args, &["-h", "--help"]) {
+        println!("{}", util::create_help(cmd, DESC, &opts, Some("[TAG...]")));
+        return Ok(());
+    }
+    let matches = opts.parse(args).context(USAGE_HINT)?;
+    let crypt = matches.opt_present("c");
+    let check_md5 = matches.opt_present("m");
+    let source = PassphraseSource::from_matches(&matches)?;
+    let identity = matches.opt_str("i").map(PathBuf::from);
+    let jobs = matches
+        .opt_get("j")?
+        .unwrap_or_else(TaskLimit::default_jobs);
+    let tags = matches.free;
+    ensure!(crypt || !check_md5, "--md5 requires --crypt");
+
+    super::process_with_config_lock(basedir, |dirpath, config| {
+        let param = VerifyParam {
+            crypt,
+            check_md5,
+            source: source.clone(),
+            identity: identity.clone(),
+            jobs,
+        };
+        process_verify(dirpath, &config, &tags, param)?;
+        Ok(None)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use md5::{Digest, Md5};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let tagdir = dirpath.join(super::super::DIRNAME_REPO).join("a");
+        std::fs::create_dir_all(&tagdir)?;
+
+        let rf = RepositoryFile {
+            name: "a_20240101.bin".to_string(),
+            md5name: "a_20240101.bin.md5sum".to_string(),
+            crypt: false,
+        };
+        std::fs::write(tagdir.join(&rf.name), "hello")?;
+        std::fs::write(
+            tagdir.join(&rf.md5name),
+            util::md5_to_str(&Md5::digest("hello")),
+        )?;
+
+        let mut config = Config::default();
+        config
+            .repository
+            .entries
+            .insert("a".to_string(), [Reverse(rf.clone())].into());
+        process_verify(
+            dirpath,
+            &config,
+            &[],
+            VerifyParam {
+                jobs: 2,
+                ..Default::default()
+            },
+        )?;
+        assert!(process_verify(
+            dirpath,
+            &config,
+            &["b".to_string()],
+            VerifyParam {
+                jobs: 2,
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        // bit rot
+        std::fs::write(tagdir.join(&rf.name), "hellO")?;
+        assert!(process_verify(
+            dirpath,
+            &config,
+            &[],
+            VerifyParam {
+                jobs: 2,
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        // lost
+        std::fs::remove_file(tagdir.join(&rf.name))?;
+        assert!(process_verify(
+            dirpath,
+            &config,
+            &[],
+            VerifyParam {
+                jobs: 2,
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_crypt_lost() -> Result<()> {
+        let tmpdir = TempDir::new("bkupman-test")?;
+        let dirpath = tmpdir.path();
+        let crypt_path = dirpath.join(super::super::DIRNAME_CRYPT);
+
+        // plain "crypt" data of name in dir_path
+        let write_crypt = |dir_path: &Path, name: &str| -> Result<()> {
+            std::fs::create_dir_all(dir_path)?;
+            let info = super::super::CryptInfo {
+                crypt: Default::default(),
+                total_size: 5,
+                fragment_size: 8.try_into()?,
+                aad: false,
+                header_version: 0,
+                compression: None,
+                parity: None,
+                chunks: None,
+            };
+            std::fs::write(
+                dir_path.join(super::super::CRYPT_INFO_NAME),
+                toml::to_string(&info)?,
+            )?;
+            std::fs::write(dir_path.join(super::super::fragment_name(name, 0)), "hello")?;
+            std::fs::write(
+                dir_path.join(super::super::fragment_md5_name(name, 0)),
+                util::md5_to_str(&Md5::digest("hello")),
+            )?;
+            Ok(())
+        };
+        let rf = |name: &str| {
+            Reverse(RepositoryFile {
+                name: name.to_string(),
+                md5name: format!("{name}.md5sum"),
+                crypt: true,
+            })
+        };
+        let verify = |config: &Config| {
+            process_verify(
+                dirpath,
+                config,
+                &[],
+                VerifyParam {
+                    crypt: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // old layout: a_20240101.bin was overwritten by a_20240102.bin
+        write_crypt(&crypt_path.join("a"), "a_20240102.bin")?;
+        // new layout
+        write_crypt(
+            &crypt_path.join("a").join("a_20240103.bin"),
+            "a_20240103.bin",
+        )?;
+        let mut config = Config::default();
+        config.repository.entries.insert(
+            "a".to_string(),
+            [
+                rf("a_20240101.bin"),
+                rf("a_20240102.bin"),
+                rf("a_20240103.bin"),
+            ]
+            .into(),
+        );
+        verify(&config)?;
+
+        // version dir lost
+        std::fs::remove_dir_all(crypt_path.join("a").join("a_20240103.bin"))?;
+        let err = verify(&config).unwrap_err();
+        assert!(format!("{err:#}").contains("1 file(s)"));
+
+        // old layout dir lost
+        std::fs::remove_dir_all(crypt_path.join("a"))?;
+        let err = verify(&config).unwrap_err();
+        assert!(format!("{err:#}").contains("3 file(s)"));
+        assert!(process_verify(
+            dirpath,
+            &config,
+            &[],
+            VerifyParam {
+                jobs: 2,
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,17 @@
-use aes_gcm::{self, aead::Aead, AeadCore, Aes256Gcm, Key, KeyInit};
-use anyhow::{anyhow, Result};
+use aes_gcm::{
+    self,
+    aead::{Aead, Payload},
+    AeadCore, Aes256Gcm, Key, KeyInit,
+};
+use anyhow::{anyhow, ensure, Result};
 use argon2::Argon2;
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
 
 /// cryptographically secure
 pub fn generate_random<const S: usize>() -> [u8; S] {
@@ -54,34 +64,457 @@ pub fn aeskey_from_password(
 
 pub const AES_KEY_SIZE: usize = 32;
 pub const AES_NONCE_SIZE: usize = 12;
+pub const AES_TAG_SIZE: usize = 16;
 
 pub type AesKey = [u8; AES_KEY_SIZE];
 pub type AesNonce = [u8; AES_NONCE_SIZE];
+pub type AesKeyCheck = [u8; AES_TAG_SIZE];
+pub type AesTag = [u8; AES_TAG_SIZE];
 
 /// key = 32 (AES 256 bit)
 /// nonce = 12 (96 bit)
 /// input = any
+/// aad = any (authenticated but not encrypted)
 /// output = the same size as input
 /// tag = 16
-pub fn encrypt_aes256gcm(key: &AesKey, input: &[u8]) -> Result<(AesNonce, Vec<u8>)> {
+pub fn encrypt_aes256gcm(key: &AesKey, input: &[u8], aad: &[u8]) -> Result<(AesNonce, Vec<u8>)> {
     let key: &Key<Aes256Gcm> = key.into();
     let cipher = Aes256Gcm::new(key);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let crypted = cipher.encrypt(&nonce, input).map_err(|err| anyhow!(err))?;
+    let crypted = cipher
+        .encrypt(&nonce, Payload { msg: input, aad })
+        .map_err(|err| anyhow!(err))?;
 
     Ok((nonce.into(), crypted))
 }
 
-pub fn decrypt_aes256gcm(key: &AesKey, nonce: AesNonce, input: &[u8]) -> Result<Vec<u8>> {
+/// aad must be the same as encryption.
+pub fn decrypt_aes256gcm(
+    key: &AesKey,
+    nonce: AesNonce,
+    input: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
     let key: &Key<Aes256Gcm> = key.into();
     let cipher = Aes256Gcm::new(key);
     let plaintext = cipher
-        .decrypt(&nonce.into(), input)
+        .decrypt(&nonce.into(), Payload { msg: input, aad })
+        .map_err(|err| anyhow!(err))?;
+
+    Ok(plaintext)
+}
+
+pub const XCHACHA_NONCE_SIZE: usize = 24;
+pub const XCHACHA_TAG_SIZE: usize = 16;
+
+pub type XChaChaNonce = [u8; XCHACHA_NONCE_SIZE];
+
+/// key = 32 (the same as [AesKey])
+/// nonce = 24 (192 bit, safe to generate randomly)
+/// input = any
+/// aad = any (authenticated but not encrypted)
+/// output = the same size as input
+/// tag = 16
+///
+/// Fast without AES instructions.
+pub fn encrypt_xchacha20poly1305(
+    key: &AesKey,
+    input: &[u8],
+    aad: &[u8],
+) -> Result<(XChaChaNonce, Vec<u8>)> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let crypted = cipher
+        .encrypt(&nonce, Payload { msg: input, aad })
+        .map_err(|err| anyhow!(err))?;
+
+    Ok((nonce.into(), crypted))
+}
+
+/// aad must be the same as encryption.
+pub fn decrypt_xchacha20poly1305(
+    key: &AesKey,
+    nonce: XChaChaNonce,
+    input: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let plaintext = cipher
+        .decrypt(&nonce.into(), Payload { msg: input, aad })
         .map_err(|err| anyhow!(err))?;
 
     Ok(plaintext)
 }
 
+/// Key-check value to validate a passphrase without saving the key.
+///
+/// GCM tag of empty plaintext with zero nonce.
+/// (random nonces for data never collide with it in practice)
+pub fn aeskey_check(key: &AesKey) -> AesKeyCheck {
+    let key: &Key<Aes256Gcm> = key.into();
+    let cipher = Aes256Gcm::new(key);
+    let nonce = AesNonce::default();
+    let tag = cipher.encrypt(&nonce.into(), &[][..]).unwrap();
+
+    tag.try_into().unwrap()
+}
+
+/// Encrypt a data key with a key-encryption key.
+///
+/// (nonce, encrypted key, tag)
+pub fn wrap_aeskey(kek: &AesKey, key: &AesKey) -> Result<(AesNonce, AesKey, AesTag)> {
+    let (nonce, crypted) = encrypt_aes256gcm(kek, key, &[])?;
+    let (wrapped, tag) = crypted.split_at(AES_KEY_SIZE);
+
+    Ok((nonce, wrapped.try_into()?, tag.try_into()?))
+}
+
+/// Decrypt a data key (fails if kek is wrong).
+pub fn unwrap_aeskey(
+    kek: &AesKey,
+    nonce: AesNonce,
+    wrapped: &AesKey,
+    tag: &AesTag,
+) -> Result<AesKey> {
+    let mut input = wrapped.to_vec();
+    input.extend_from_slice(tag);
+    let key = decrypt_aes256gcm(kek, nonce, &input, &[])?;
+
+    Ok(key.try_into().unwrap())
+}
+
+pub const X25519_KEY_SIZE: usize = 32;
+
+/// Secret or public key
+pub type X25519Key = [u8; X25519_KEY_SIZE];
+
+/// (secret, public)
+pub fn x25519_keypair_new() -> (X25519Key, X25519Key) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+
+    (secret.to_bytes(), public.to_bytes())
+}
+
+pub fn x25519_public(secret: &X25519Key) -> X25519Key {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+/// Create a key-encryption key for the recipient with a new ephemeral key pair.
+///
+/// (ephemeral public key, kek)
+/// The ephemeral secret is discarded; only the recipient can derive kek again.
+pub fn x25519_kek_seal(recipient: &X25519Key) -> Result<(X25519Key, AesKey)> {
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*recipient));
+    ensure!(shared.was_contributory(), "Invalid public key");
+    let kek = x25519_hkdf(shared.as_bytes(), &ephemeral_public, recipient);
+
+    Ok((ephemeral_public, kek))
+}
+
+/// Derive the key-encryption key with the recipient secret key.
+pub fn x25519_kek_open(secret: &X25519Key, ephemeral: &X25519Key) -> Result<AesKey> {
+    let secret = StaticSecret::from(*secret);
+    let recipient = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral));
+    ensure!(shared.was_contributory(), "Invalid ephemeral key");
+
+    Ok(x25519_hkdf(shared.as_bytes(), ephemeral, &recipient))
+}
+
+/// HKDF-SHA256 (salt = ephemeral || recipient)
+fn x25519_hkdf(shared: &[u8], ephemeral: &X25519Key, recipient: &X25519Key) -> AesKey {
+    let mut salt = Vec::with_capacity(X25519_KEY_SIZE * 2);
+    salt.extend_from_slice(ephemeral);
+    salt.extend_from_slice(recipient);
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut kek = AesKey::default();
+    hkdf.expand(b"bkupman-x25519", &mut kek).unwrap();
+
+    kek
+}
+
+/// Chunk ID (HMAC-SHA256)
+pub const CHUNK_ID_SIZE: usize = 32;
+pub type ChunkId = [u8; CHUNK_ID_SIZE];
+pub type ChunkIdKey = [u8; 32];
+
+/// Derive the key of chunk IDs from the data key (HKDF-SHA256).
+pub fn chunk_id_key(data_key: &AesKey) -> ChunkIdKey {
+    let hkdf = Hkdf::<Sha256>::new(None, data_key);
+    let mut key = ChunkIdKey::default();
+    hkdf.expand(b"bkupman-chunk-id", &mut key).unwrap();
+
+    key
+}
+
+/// Keyed hash of plain data.
+///
+/// Equal data has the same ID, but it cannot be guessed without the key.
+pub fn chunk_id(key: &ChunkIdKey, data: &[u8]) -> ChunkId {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(data);
+
+    mac.finalize().into_bytes().into()
+}
+
+/// Magic bytes at the beginning of each encrypted fragment.
+pub const FRAGMENT_MAGIC: [u8; 8] = *b"BKUPMAN\0";
+/// The current fragment header version
+pub const FRAGMENT_VERSION: u16 = 1;
+
+/// Encrypted fragment header.
+///
+/// Version 1 (all integers are little endian):
+///
+/// | offset | size | field                                  |
+/// |-------:|-----:|----------------------------------------|
+/// |      0 |    8 | magic [FRAGMENT_MAGIC]                 |
+/// |      8 |    2 | header length (= ciphertext offset)    |
+/// |     10 |    2 | format version                         |
+/// |     12 |    1 | cipher id ([CipherId])                 |
+/// |     13 |    1 | KDF id ([KdfId])                       |
+/// |     14 |   16 | Argon2 salt (zero if not Argon2)       |
+/// |     30 |   12 | Argon2 m_cost, t_cost, p_cost (u32 x3) |
+/// |     42 |    8 | fragment index (u64)                   |
+/// |     50 |    N | nonce (AES: 12, XChaCha20: 24)         |
+///
+/// Ciphertext (+ tag:16) follows.
+///
+/// Version 0 (no magic, AES only): Argon2 salt:16, m:4, t:4, p:4, nonce:12
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub version: u16,
+    pub cipher: CipherId,
+    pub kdf: KdfId,
+    pub salt: Argon2Salt,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    /// None if version 0
+    pub index: Option<u64>,
+    /// [CipherId::nonce_size] bytes
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherId {
+    Aes256Gcm = 1,
+    XChaCha20Poly1305 = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfId {
+    Argon2id = 1,
+    /// Data key is wrapped for each public key (in metadata)
+    X25519Hkdf = 2,
+}
+
+impl TryFrom<u8> for CipherId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Aes256Gcm),
+            2 => Ok(Self::XChaCha20Poly1305),
+            _ => Err(anyhow!("Unknown cipher id: {value}")),
+        }
+    }
+}
+
+impl CipherId {
+    pub fn nonce_size(self) -> usize {
+        match self {
+            Self::Aes256Gcm => AES_NONCE_SIZE,
+            Self::XChaCha20Poly1305 => XCHACHA_NONCE_SIZE,
+        }
+    }
+
+    pub fn tag_size(self) -> usize {
+        match self {
+            Self::Aes256Gcm => AES_TAG_SIZE,
+            Self::XChaCha20Poly1305 => XCHACHA_TAG_SIZE,
+        }
+    }
+
+    /// (nonce, ciphertext + tag)
+    pub fn encrypt(self, key: &AesKey, input: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            Self::Aes256Gcm => {
+                let (nonce, crypted) = encrypt_aes256gcm(key, input, aad)?;
+                Ok((nonce.to_vec(), crypted))
+            }
+            Self::XChaCha20Poly1305 => {
+                let (nonce, crypted) = encrypt_xchacha20poly1305(key, input, aad)?;
+                Ok((nonce.to_vec(), crypted))
+            }
+        }
+    }
+
+    /// nonce must be [Self::nonce_size] bytes.
+    pub fn decrypt(self, key: &AesKey, nonce: &[u8], input: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Aes256Gcm => decrypt_aes256gcm(key, nonce.try_into()?, input, aad),
+            Self::XChaCha20Poly1305 => {
+                decrypt_xchacha20poly1305(key, nonce.try_into()?, input, aad)
+            }
+        }
+    }
+}
+
+impl TryFrom<u8> for KdfId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Argon2id),
+            2 => Ok(Self::X25519Hkdf),
+            _ => Err(anyhow!("Unknown KDF id: {value}")),
+        }
+    }
+}
+
+impl FragmentHeader {
+    /// Header size of the version and cipher (None if unsupported).
+    pub fn size_of(version: u16, cipher: CipherId) -> Option<usize> {
+        match (version, cipher) {
+            (0, CipherId::Aes256Gcm) => Some(ARGON2_SALT_SIZE + 4 * 3 + AES_NONCE_SIZE),
+            (1, _) => Some(
+                FRAGMENT_MAGIC.len()
+                    + 2
+                    + 2
+                    + 1
+                    + 1
+                    + ARGON2_SALT_SIZE
+                    + 4 * 3
+                    + 8
+                    + cipher.nonce_size(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The current version header.
+    pub fn new(
+        cipher: CipherId,
+        salt: Argon2Salt,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        index: u64,
+        nonce: Vec<u8>,
+    ) -> Self {
+        assert_eq!(nonce.len(), cipher.nonce_size());
+        Self {
+            version: FRAGMENT_VERSION,
+            cipher,
+            kdf: KdfId::Argon2id,
+            salt,
+            m_cost,
+            t_cost,
+            p_cost,
+            index: Some(index),
+            nonce,
+        }
+    }
+
+    /// The current version header without passphrase.
+    pub fn new_x25519(cipher: CipherId, index: u64, nonce: Vec<u8>) -> Self {
+        Self {
+            kdf: KdfId::X25519Hkdf,
+            ..Self::new(cipher, Default::default(), 0, 0, 0, index, nonce)
+        }
+    }
+
+    /// Header length (= ciphertext offset).
+    pub fn header_len(&self) -> usize {
+        Self::size_of(self.version, self.cipher).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(self.header_len());
+        if self.version == 0 {
+            buf.put(&self.salt[..]);
+            buf.put_u32_le(self.m_cost);
+            buf.put_u32_le(self.t_cost);
+            buf.put_u32_le(self.p_cost);
+            buf.put(&self.nonce[..]);
+        } else {
+            buf.put(&FRAGMENT_MAGIC[..]);
+            buf.put_u16_le(self.header_len() as u16);
+            buf.put_u16_le(self.version);
+            buf.put_u8(self.cipher as u8);
+            buf.put_u8(self.kdf as u8);
+            buf.put(&self.salt[..]);
+            buf.put_u32_le(self.m_cost);
+            buf.put_u32_le(self.t_cost);
+            buf.put_u32_le(self.p_cost);
+            buf.put_u64_le(self.index.unwrap_or_default());
+            buf.put(&self.nonce[..]);
+        }
+        assert_eq!(buf.len(), self.header_len());
+
+        buf.to_vec()
+    }
+
+    /// Version 0 is assumed if magic is not found.
+    pub fn parse(mut buf: &[u8]) -> Result<Self> {
+        let version0 = !buf.starts_with(&FRAGMENT_MAGIC);
+        let (version, cipher, kdf) = if version0 {
+            let cipher = CipherId::Aes256Gcm;
+            ensure!(
+                buf.len() >= Self::size_of(0, cipher).unwrap(),
+                "Header too short"
+            );
+            (0, cipher, KdfId::Argon2id)
+        } else {
+            ensure!(buf.len() >= FRAGMENT_MAGIC.len() + 5, "Header too short");
+            buf.advance(FRAGMENT_MAGIC.len());
+            let len = buf.get_u16_le() as usize;
+            let version = buf.get_u16_le();
+            let cipher = buf.get_u8().try_into()?;
+            ensure!(
+                Self::size_of(version, cipher) == Some(len),
+                "Unsupported version: {version} (header length {len})"
+            );
+            // magic, length, version and cipher have been read
+            ensure!(
+                buf.len() + FRAGMENT_MAGIC.len() + 5 >= len,
+                "Header too short"
+            );
+            let kdf = buf.get_u8().try_into()?;
+            (version, cipher, kdf)
+        };
+
+        let mut salt = Argon2Salt::default();
+        buf.copy_to_slice(&mut salt);
+        let m_cost = buf.get_u32_le();
+        let t_cost = buf.get_u32_le();
+        let p_cost = buf.get_u32_le();
+        let index = if version0 {
+            None
+        } else {
+            Some(buf.get_u64_le())
+        };
+        let mut nonce = vec![0; cipher.nonce_size()];
+        buf.copy_to_slice(&mut nonce);
+
+        Ok(Self {
+            version,
+            cipher,
+            kdf,
+            salt,
+            m_cost,
+            t_cost,
+            p_cost,
+            index,
+            nonce,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,11 +523,11 @@ mod tests {
     fn test_password_hash_key() -> Result<()> {
         let pwd: &str = "password";
 
-        let (salt1, m, t, p, key1) = aeskey_new_from_password(&pwd);
+        let (salt1, m, t, p, key1) = aeskey_new_from_password(pwd);
         let key2 = aeskey_from_password(salt1, m, t, p, pwd)?;
         assert_eq!(key1, key2);
 
-        let (salt3, _, _, _, key3) = aeskey_new_from_password(&pwd);
+        let (salt3, _, _, _, key3) = aeskey_new_from_password(pwd);
         assert_ne!(salt1, salt3);
         assert_ne!(key1, key3);
 
@@ -106,11 +539,110 @@ mod tests {
         let key: AesKey = generate_random();
         let plaintext = b"hello";
 
-        let (nonce, ciphertext) = encrypt_aes256gcm(&key, plaintext)?;
+        let (nonce, ciphertext) = encrypt_aes256gcm(&key, plaintext, b"aad")?;
         assert_ne!(plaintext, &ciphertext.as_ref());
 
-        let decrypted = decrypt_aes256gcm(&key, nonce, &ciphertext)?;
+        let decrypted = decrypt_aes256gcm(&key, nonce, &ciphertext, b"aad")?;
+        assert_eq!(&decrypted.as_ref(), plaintext);
+        assert!(decrypt_aes256gcm(&key, nonce, &ciphertext, b"aaD").is_err());
+
+        let (nonce, ciphertext) = encrypt_xchacha20poly1305(&key, plaintext, b"aad")?;
+        assert_eq!(ciphertext.len(), plaintext.len() + XCHACHA_TAG_SIZE);
+        let decrypted = decrypt_xchacha20poly1305(&key, nonce, &ciphertext, b"aad")?;
         assert_eq!(&decrypted.as_ref(), plaintext);
+        assert!(decrypt_xchacha20poly1305(&key, nonce, &ciphertext, b"aaD").is_err());
+
+        let key2: AesKey = generate_random();
+        assert_eq!(aeskey_check(&key), aeskey_check(&key));
+        assert_ne!(aeskey_check(&key), aeskey_check(&key2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_x25519_kek() -> Result<()> {
+        let (secret, public) = x25519_keypair_new();
+        assert_eq!(x25519_public(&secret), public);
+
+        let (ephemeral, kek) = x25519_kek_seal(&public)?;
+        assert_eq!(x25519_kek_open(&secret, &ephemeral)?, kek);
+
+        let (ephemeral2, kek2) = x25519_kek_seal(&public)?;
+        assert_ne!(ephemeral, ephemeral2);
+        assert_ne!(kek, kek2);
+
+        let (secret2, _) = x25519_keypair_new();
+        assert_ne!(x25519_kek_open(&secret2, &ephemeral)?, kek);
+        assert!(x25519_kek_seal(&[0; X25519_KEY_SIZE]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fragment_header() -> Result<()> {
+        let header = FragmentHeader::new(
+            CipherId::XChaCha20Poly1305,
+            generate_random(),
+            1,
+            2,
+            3,
+            4,
+            generate_random::<XCHACHA_NONCE_SIZE>().to_vec(),
+        );
+        let buf = header.to_bytes();
+        assert_eq!(buf.len(), 74);
+        assert_eq!(FragmentHeader::parse(&buf)?, header);
+        assert!(FragmentHeader::parse(&buf[..buf.len() - 1]).is_err());
+
+        let header = FragmentHeader::new(
+            CipherId::Aes256Gcm,
+            generate_random(),
+            1,
+            2,
+            3,
+            4,
+            generate_random::<AES_NONCE_SIZE>().to_vec(),
+        );
+        let buf = header.to_bytes();
+        assert_eq!(buf.len(), 62);
+        assert!(buf.starts_with(&FRAGMENT_MAGIC));
+        assert_eq!(FragmentHeader::parse(&buf)?, header);
+        assert!(FragmentHeader::parse(&buf[..buf.len() - 1]).is_err());
+
+        // unknown version
+        let mut buf2 = buf.clone();
+        buf2[10] = 99;
+        assert!(FragmentHeader::parse(&buf2).is_err());
+        // unknown cipher
+        let mut buf2 = buf.clone();
+        buf2[12] = 99;
+        assert!(FragmentHeader::parse(&buf2).is_err());
+
+        // old format without magic
+        let header = FragmentHeader {
+            version: 0,
+            index: None,
+            ..header
+        };
+        let buf = header.to_bytes();
+        assert_eq!(buf.len(), 40);
+        assert_eq!(FragmentHeader::parse(&buf)?, header);
+        assert!(FragmentHeader::parse(&buf[..buf.len() - 1]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_aeskey() -> Result<()> {
+        let kek: AesKey = generate_random();
+        let key: AesKey = generate_random();
+
+        let (nonce, wrapped, tag) = wrap_aeskey(&kek, &key)?;
+        assert_ne!(wrapped, key);
+        assert_eq!(unwrap_aeskey(&kek, nonce, &wrapped, &tag)?, key);
+
+        let kek2: AesKey = generate_random();
+        assert!(unwrap_aeskey(&kek2, nonce, &wrapped, &tag).is_err());
 
         Ok(())
     }
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, ensure, Result};
 use getopts::Options;
-use tokio::io::AsyncReadExt;
+use md5::{Digest, Md5};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 /// The library getopts workaround.
 ///
@@ -11,7 +12,7 @@ use tokio::io::AsyncReadExt;
 pub fn find_option(args: &[impl AsRef<str>], optstrs: &[&str]) -> bool {
     for arg in args {
         let arg = arg.as_ref();
-        if optstrs.iter().any(|&s| arg == s) {
+        if optstrs.contains(&arg) {
             return true;
         }
     }
@@ -51,6 +52,23 @@ pub fn parse_size(s: &str) -> Result<u64> {
     num.checked_mul(unit).ok_or_else(|| anyhow!("overflow"))
 }
 
+/// "12h", "1d", "2w" (hours, days, weeks)
+pub fn parse_interval(s: &str) -> Result<chrono::TimeDelta> {
+    ensure!(s.is_ascii(), "string is not ascii");
+    ensure!(!s.is_empty(), "string is empty");
+
+    let (numstr, unit) = s.split_at(s.len() - 1);
+    let num: i64 = numstr.parse()?;
+    let delta = match unit {
+        "h" | "H" => chrono::TimeDelta::try_hours(num),
+        "d" | "D" => chrono::TimeDelta::try_days(num),
+        "w" | "W" => chrono::TimeDelta::try_weeks(num),
+        _ => return Err(anyhow!("invalid unit (h, d, w): {s}")),
+    };
+
+    delta.ok_or_else(|| anyhow!("overflow"))
+}
+
 pub fn size_to_human_readable(size: u64) -> String {
     if size < 1024 {
         return format!("{size} B");
@@ -120,7 +138,7 @@ pub fn xorshift64(mut x: u64) -> u64 {
 }
 
 pub fn xorshift64_fill(v: &mut [u8], state: u64) -> u64 {
-    assert!(v.len() % 8 == 0);
+    assert!(v.len().is_multiple_of(8));
 
     let mut x = state;
     for i in (0..v.len()).step_by(8) {
@@ -147,6 +165,290 @@ pub async fn read_fully(file: &mut tokio::fs::File, buf: &mut [u8]) -> Result<us
     Ok(cur)
 }
 
+/// Write to a file and fsync.
+pub async fn write_sync(path: &std::path::Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+/// fsync a directory to make rename/create/delete in it persistent.
+pub fn sync_dir(path: &std::path::Path) -> Result<()> {
+    // opening a directory is not allowed on Windows
+    if cfg!(unix) {
+        std::fs::File::open(path)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// [sync_dir] on the blocking thread pool (for async fns).
+pub async fn sync_dir_async(path: &std::path::Path) -> Result<()> {
+    let path = path.to_path_buf();
+    blocking(move || sync_dir(&path)).await
+}
+
+/// Write to "path.tmp", fsync and rename to path.
+///
+/// The file is replaced atomically; either the old or the new contents remain.
+pub fn write_atomic(path: &std::path::Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    write_atomic_impl(path, contents.as_ref(), false)
+}
+
+/// [write_atomic] on the blocking thread pool (for async fns).
+pub async fn write_atomic_async(path: &std::path::Path, contents: Vec<u8>) -> Result<()> {
+    let path = path.to_path_buf();
+    blocking(move || write_atomic(&path, contents)).await
+}
+
+/// [write_atomic] with permission 0600 (unix only).
+pub fn write_atomic_private(path: &std::path::Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    write_atomic_impl(path, contents.as_ref(), true)
+}
+
+/// "path.tmp"
+pub fn tmp_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    tmp_path.into()
+}
+
+fn write_atomic_impl(path: &std::path::Path, contents: &[u8], private: bool) -> Result<()> {
+    use std::io::Write;
+
+    let tmp_path = tmp_path(path);
+
+    // permission is applied on creation only
+    let _ = std::fs::remove_file(&tmp_path);
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = opts.open(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&tmp_path, path)?;
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            parent
+        };
+        sync_dir(parent)?;
+    }
+
+    Ok(())
+}
+
+/// Total size of files under the directory (recursive).
+/// Return 0 if not exists.
+pub fn dir_size(path: &std::path::Path) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut total = 0;
+    for entry in path.read_dir()? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else {
+            total += meta.len();
+        }
+    }
+
+    Ok(total)
+}
+
+pub async fn md5_file(path: &std::path::Path) -> Result<[u8; MD5LEN]> {
+    const BUFSIZE: usize = 64 * 1024;
+
+    // read and hash on the blocking thread pool
+    let path = path.to_path_buf();
+    blocking(move || {
+        let mut fin = std::fs::File::open(path)?;
+        let mut buf = vec![0u8; BUFSIZE];
+        let mut hasher = Md5::new();
+        loop {
+            let read_size = std::io::Read::read(&mut fin, &mut buf)?;
+            if read_size == 0 {
+                break;
+            }
+            hasher.update(&buf[..read_size]);
+        }
+
+        Ok(hasher.finalize().into())
+    })
+    .await
+}
+
+/// Run CPU-bound work (hash, compression, encryption) on the blocking thread pool.
+pub async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    // JoinError happens only if cancel or panic
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// MemAvailable in /proc/meminfo (None if unknown).
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    kb.checked_mul(1024)
+}
+
+/// Limit of simultaneous tasks and of their buffer memory.
+pub struct TaskLimit {
+    jobs: tokio::sync::Semaphore,
+    /// permits in [Self::MEMORY_UNIT]
+    memory: tokio::sync::Semaphore,
+    memory_units: u32,
+}
+
+/// Held while a task is running.
+pub struct TaskPermit<'a> {
+    _job: tokio::sync::SemaphorePermit<'a>,
+    _memory: tokio::sync::SemaphorePermit<'a>,
+}
+
+impl TaskLimit {
+    const MEMORY_UNIT: u64 = 1 << 20;
+    /// If the available memory is unknown
+    const MEMORY_FALLBACK: u64 = 1 << 30;
+
+    /// jobs: 0 is regarded as 1, memory: in bytes (u64::MAX for no limit)
+    pub fn new(jobs: usize, memory: u64) -> Self {
+        let max_units = (tokio::sync::Semaphore::MAX_PERMITS as u64).min(u32::MAX as u64);
+        let memory_units = memory.div_ceil(Self::MEMORY_UNIT).clamp(1, max_units) as u32;
+        Self {
+            jobs: tokio::sync::Semaphore::new(jobs.max(1)),
+            memory: tokio::sync::Semaphore::new(memory_units as usize),
+            memory_units,
+        }
+    }
+
+    /// The number of CPUs.
+    pub fn default_jobs() -> usize {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    }
+
+    /// Half of the available memory.
+    pub fn default_memory() -> u64 {
+        available_memory().map_or(Self::MEMORY_FALLBACK, |size| size / 2)
+    }
+
+    /// Wait for a job slot and `memory` bytes in the budget.
+    ///
+    /// A task larger than the whole budget runs alone.
+    pub async fn acquire(&self, memory: u64) -> Result<TaskPermit<'_>> {
+        let units = memory
+            .div_ceil(Self::MEMORY_UNIT)
+            .clamp(1, self.memory_units as u64) as u32;
+        // always in this order (no deadlock)
+        let job = self.jobs.acquire().await?;
+        let memory = self.memory.acquire_many(units).await?;
+
+        Ok(TaskPermit {
+            _job: job,
+            _memory: memory,
+        })
+    }
+}
+
+/// Copy src to dst (created or truncated) and return (MD5, size) of the bytes written.
+///
+/// Read once and synced.
+pub async fn copy_md5(src: &std::path::Path, dst: &std::path::Path) -> Result<([u8; MD5LEN], u64)> {
+    const BUFSIZE: usize = 1024 * 1024;
+
+    let (src, dst) = (src.to_path_buf(), dst.to_path_buf());
+    blocking(move || {
+        let mut fin = std::fs::File::open(src)?;
+        let mut fout = std::fs::File::create(dst)?;
+        let mut buf = vec![0u8; BUFSIZE];
+        let mut hasher = Md5::new();
+        let mut size = 0u64;
+        loop {
+            let read_size = std::io::Read::read(&mut fin, &mut buf)?;
+            if read_size == 0 {
+                break;
+            }
+            std::io::Write::write_all(&mut fout, &buf[..read_size])?;
+            hasher.update(&buf[..read_size]);
+            size += read_size as u64;
+        }
+        fout.sync_all()?;
+
+        Ok((hasher.finalize().into(), size))
+    })
+    .await
+}
+
+/// Line-based diff (changed lines only, "-" removed, "+" added).
+pub fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // skip common prefix and suffix
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    // LCS table: lcs[i][j] = LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff += &format!("-{}\n", old[i]);
+            i += 1;
+        } else {
+            diff += &format!("+{}\n", new[j]);
+            j += 1;
+        }
+    }
+
+    diff
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -185,6 +487,56 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_interval() -> Result<()> {
+        assert_eq!(parse_interval("12h")?, chrono::TimeDelta::hours(12));
+        assert_eq!(parse_interval("1d")?, chrono::TimeDelta::days(1));
+        assert_eq!(parse_interval("2W")?, chrono::TimeDelta::weeks(2));
+
+        assert!(parse_interval("").is_err());
+        assert!(parse_interval("1").is_err());
+        assert!(parse_interval("d").is_err());
+        assert!(parse_interval("1m").is_err());
+        assert!(parse_interval(&format!("{}w", i64::MAX)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_task_limit() -> Result<()> {
+        use std::time::Duration;
+        const MIB: u64 = 1 << 20;
+
+        let rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let wait = Duration::from_millis(100);
+
+            // 2 jobs
+            let limit = TaskLimit::new(2, 100 * MIB);
+            let p1 = limit.acquire(MIB).await?;
+            let _p2 = limit.acquire(MIB).await?;
+            assert!(tokio::time::timeout(wait, limit.acquire(MIB))
+                .await
+                .is_err());
+            drop(p1);
+            let _p3 = limit.acquire(MIB).await?;
+
+            // 3 MiB for 10 jobs
+            let limit = TaskLimit::new(10, 3 * MIB);
+            let p1 = limit.acquire(2 * MIB).await?;
+            assert!(tokio::time::timeout(wait, limit.acquire(2 * MIB))
+                .await
+                .is_err());
+            let p2 = limit.acquire(MIB).await?;
+            // larger than the budget: runs alone
+            drop(p1);
+            drop(p2);
+            let _p3 = limit.acquire(100 * MIB).await?;
+
+            Ok(())
+        })
+    }
+
     #[test]
     fn test_size_to_human_readable() {
         for size in 0..1024 {
@@ -213,4 +565,29 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_copy_md5() -> Result<()> {
+        let tmpdir = tempdir::TempDir::new("bkupman-test")?;
+        let src = tmpdir.path().join("src");
+        let dst = tmpdir.path().join("dst");
+        let data: Vec<u8> = (0..3_000_000u32).map(|x| (x % 251) as u8).collect();
+        std::fs::write(&src, &data)?;
+
+        let rt = tokio::runtime::Runtime::new()?;
+        let (md5, size) = rt.block_on(copy_md5(&src, &dst))?;
+        assert_eq!(md5, <[u8; MD5LEN]>::from(Md5::digest(&data)));
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(std::fs::read(&dst)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(line_diff("a\nb\nc\n", "a\nb\nc\n"), "");
+        assert_eq!(line_diff("a\nb\nc\n", "a\nB\nc\nd\n"), "-b\n+B\n+d\n");
+        assert_eq!(line_diff("", "a\n"), "+a\n");
+        assert_eq!(line_diff("a\nb\n", "b\n"), "-a\n");
+    }
 }
@@ -1,4 +1,7 @@
-use std::{fs, path::Path, usize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use serial_test::serial;
@@ -42,6 +45,15 @@ fn create_files(dirpath: &Path, count: usize) -> Result<()> {
     Ok(())
 }
 
+// Return crypt/<tag>/<version> of the only version in tag
+fn crypt_version_dir(dirpath: &Path, tag: &str) -> Result<PathBuf> {
+    let dir = fs::read_dir(dirpath.join("crypt").join(tag))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.is_dir())
+        .unwrap();
+    Ok(dir)
+}
+
 #[test]
 #[serial]
 fn inbox_many() -> Result<()> {
@@ -59,3 +71,280 @@ fn inbox_many() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn crypt_plain_restore() -> Result<()> {
+    let dir = TempDir::new("bkupman-test")?;
+    let dirpath = dir.path();
+    let dirstr = dirpath.to_str().unwrap();
+
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "init"];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "key", "plain"];
+    bkupman::entry_point(&argv)?;
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "test-file",
+        "-s",
+        "2500k",
+        "-r",
+    ];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "inbox"];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "verify"];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "crypt", "-f", "1m"];
+    bkupman::entry_point(&argv)?;
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "verify",
+        "--crypt",
+        "--md5",
+    ];
+    bkupman::entry_point(&argv)?;
+
+    // rebuild the index from repo/ and crypt/
+    fs::remove_file(dirpath.join("config.toml"))?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "reindex"];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "verify"];
+    bkupman::entry_point(&argv)?;
+
+    let tag = "testfile-00000";
+    let repo_file = fs::read_dir(dirpath.join("repo").join(tag))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "bin")
+        .unwrap();
+    let name = repo_file.file_name().unwrap().to_str().unwrap();
+    let original = fs::read(&repo_file)?;
+
+    let outdir = dirpath.join("out");
+    let outstr = outdir.to_str().unwrap();
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "restore",
+        "-o",
+        outstr,
+        tag,
+    ];
+    bkupman::entry_point(&argv)?;
+    assert_eq!(fs::read(outdir.join(name))?, original);
+
+    let cryptdir = crypt_version_dir(dirpath, tag)?;
+    let outfile = dirpath.join("recovered.bin");
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "recover",
+        cryptdir.to_str().unwrap(),
+        outfile.to_str().unwrap(),
+    ];
+    bkupman::entry_point(&argv)?;
+    assert_eq!(fs::read(&outfile)?, original);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn crypt_aes_restore() -> Result<()> {
+    let dir = TempDir::new("bkupman-test")?;
+    let dirpath = dir.path();
+    let dirstr = dirpath.to_str().unwrap();
+
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "init"];
+    bkupman::entry_point(&argv)?;
+
+    let passfile = dirpath.join("passphrase");
+    fs::write(&passfile, "password\n")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&passfile, fs::Permissions::from_mode(0o600))?;
+    }
+    let passstr = passfile.to_str().unwrap();
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "key",
+        "--no-save",
+        "--passphrase-file",
+        passstr,
+        "aes",
+    ];
+    bkupman::entry_point(&argv)?;
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "test-file",
+        "-s",
+        "2500k",
+        "-r",
+    ];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "inbox"];
+    bkupman::entry_point(&argv)?;
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "crypt",
+        "-f",
+        "1m",
+        "--passphrase-file",
+        passstr,
+    ];
+    bkupman::entry_point(&argv)?;
+
+    let tag = "testfile-00000";
+    let cryptdir = crypt_version_dir(dirpath, tag)?;
+    let fragment = fs::read_dir(&cryptdir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "000001")
+        .unwrap();
+    let argv = [&get_argv0(), "-t", "inspect", fragment.to_str().unwrap()];
+    bkupman::entry_point(&argv)?;
+
+    let repo_file = fs::read_dir(dirpath.join("repo").join(tag))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "bin")
+        .unwrap();
+    let name = repo_file.file_name().unwrap().to_str().unwrap();
+    let original = fs::read(&repo_file)?;
+
+    let outdir = dirpath.join("out");
+    let outstr = outdir.to_str().unwrap();
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "restore",
+        "--passphrase-file",
+        passstr,
+        "-o",
+        outstr,
+        tag,
+    ];
+    bkupman::entry_point(&argv)?;
+    assert_eq!(fs::read(outdir.join(name))?, original);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn crypt_x25519_restore() -> Result<()> {
+    let dir = TempDir::new("bkupman-test")?;
+    let dirpath = dir.path();
+    let dirstr = dirpath.to_str().unwrap();
+
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "init"];
+    bkupman::entry_point(&argv)?;
+
+    // private key is kept outside of the repository
+    let keydir = TempDir::new("bkupman-test")?;
+    let keyfile = keydir.path().join("identity");
+    let keystr = keyfile.to_str().unwrap();
+    let argv = [&get_argv0(), "-t", "key", "gen-keypair", keystr];
+    bkupman::entry_point(&argv)?;
+    let pubkey = fs::read_to_string(&keyfile)?
+        .lines()
+        .find_map(|line| line.strip_prefix("# public key: ").map(str::to_string))
+        .unwrap();
+
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "key",
+        "x25519",
+        "-r",
+        &pubkey,
+    ];
+    bkupman::entry_point(&argv)?;
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "test-file",
+        "-s",
+        "2500k",
+        "-r",
+    ];
+    bkupman::entry_point(&argv)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "inbox"];
+    bkupman::entry_point(&argv)?;
+    // no secret is needed
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "crypt",
+        "-f",
+        "1m",
+        "-p",
+        "2+1",
+    ];
+    bkupman::entry_point(&argv)?;
+
+    // lost fragment is regenerated without keys
+    let tag = "testfile-00000";
+    let cryptdir = crypt_version_dir(dirpath, tag)?;
+    let fragment = fs::read_dir(&cryptdir)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "000001")
+        .unwrap();
+    let fragment_data = fs::read(&fragment)?;
+    fs::remove_file(&fragment)?;
+    let argv = [&get_argv0(), "-t", "-C", dirstr, "repair"];
+    bkupman::entry_point(&argv)?;
+    assert_eq!(fs::read(&fragment)?, fragment_data);
+    // or reconstructed while restoring
+    fs::remove_file(&fragment)?;
+
+    let repo_file = fs::read_dir(dirpath.join("repo").join(tag))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().unwrap() == "bin")
+        .unwrap();
+    let name = repo_file.file_name().unwrap().to_str().unwrap();
+    let original = fs::read(&repo_file)?;
+
+    let outdir = dirpath.join("out");
+    let outstr = outdir.to_str().unwrap();
+    let argv = [
+        &get_argv0(),
+        "-t",
+        "-C",
+        dirstr,
+        "restore",
+        "-i",
+        keystr,
+        "-o",
+        outstr,
+        tag,
+    ];
+    bkupman::entry_point(&argv)?;
+    assert_eq!(fs::read(outdir.join(name))?, original);
+
+    Ok(())
+}